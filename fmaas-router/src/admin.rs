@@ -0,0 +1,246 @@
+//! Admin API served on the probe port, for managing model routes at runtime.
+//!
+//! Changes are applied to the running router immediately and, if enabled, written
+//! back to the model map config. Changes which aren't persisted last until the
+//! config file itself changes and is reloaded.
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{clients::Upstreams, ModelMap, ModelMapV3, ModelRoute, SECTIONS};
+
+struct Admin {
+    upstreams: Arc<Upstreams>,
+    token: String,
+    /// Model map config to write changes back to, if enabled.
+    persist_path: Option<PathBuf>,
+    /// Serializes changes so that they are persisted in the order they are applied.
+    update_lock: Mutex<()>,
+}
+
+/// Routes of the admin API, all of which require `token` as a bearer token.
+///
+/// - `GET /admin/routes`: the whole model map, in the V3 format
+/// - `GET /admin/routes/{section}`: the routes of a section
+/// - `GET|PUT|DELETE /admin/routes/{section}/{model}`: a single route, where the body
+///   of a `PUT` is a route as it would be configured in the model map, in YAML or JSON
+///
+/// Changes are validated as a model map config is when it is loaded, and are
+/// rejected with the errors found if they would make it invalid.
+pub(crate) fn router(
+    upstreams: Arc<Upstreams>,
+    token: String,
+    persist_path: Option<PathBuf>,
+) -> Router {
+    let admin = Arc::new(Admin {
+        upstreams,
+        token,
+        persist_path,
+        update_lock: Mutex::new(()),
+    });
+    Router::new()
+        .route("/admin/routes", get(get_model_map))
+        .route("/admin/routes/:section", get(list_routes))
+        .route(
+            "/admin/routes/:section/*model",
+            get(get_route).put(put_route).delete(delete_route),
+        )
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+
+async fn authorize<B>(
+    State(admin): State<Arc<Admin>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), admin.token.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid admin token".to_string(),
+        )),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn get_model_map(State(admin): State<Arc<Admin>>) -> Result<Json<ModelMapV3>, ApiError> {
+    Ok(Json(current_model_map(&admin).await?.into_v3()))
+}
+
+async fn list_routes(
+    State(admin): State<Arc<Admin>>,
+    Path(section): Path<String>,
+) -> Result<Json<BTreeMap<String, ModelRoute>>, ApiError> {
+    check_section(&section)?;
+    let model_map = current_model_map(&admin).await?;
+    let routes = model_map
+        .routes(&section)
+        .map(|routes| routes.clone().into_iter().collect())
+        .unwrap_or_default();
+    Ok(Json(routes))
+}
+
+async fn get_route(
+    State(admin): State<Arc<Admin>>,
+    Path((section, model)): Path<(String, String)>,
+) -> Result<Json<ModelRoute>, ApiError> {
+    check_section(&section)?;
+    let model = model_name(&model);
+    current_model_map(&admin)
+        .await?
+        .routes(&section)
+        .and_then(|routes| routes.get(model))
+        .map(|route| Json(route.clone()))
+        .ok_or_else(|| not_found(&section, model))
+}
+
+async fn put_route(
+    State(admin): State<Arc<Admin>>,
+    Path((section, model)): Path<(String, String)>,
+    body: String,
+) -> Result<(StatusCode, Json<ModelRoute>), ApiError> {
+    check_section(&section)?;
+    let model = model_name(&model).to_string();
+    // YAML is a superset of JSON, so either can be used for the body
+    let route: ModelRoute = serde_yaml::from_str(&body)
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("Invalid route: {e}")))?;
+
+    let _guard = admin.update_lock.lock().await;
+    let (previous, model_map) = admin
+        .upstreams
+        .update(|model_map| {
+            let routes = model_map.routes_mut(&section).unwrap();
+            routes.insert(model.clone(), route.clone())
+        })
+        .await
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    match &previous {
+        Some(previous) => {
+            info!("Admin API updated {section} model [{model}]: {previous} -> {route}")
+        }
+        None => info!("Admin API added {section} model [{model}] -> {route}"),
+    }
+    persist(&admin, &model_map).await?;
+    let status = match previous {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::CREATED,
+    };
+    Ok((status, Json(route)))
+}
+
+async fn delete_route(
+    State(admin): State<Arc<Admin>>,
+    Path((section, model)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    check_section(&section)?;
+    let model = model_name(&model);
+
+    let _guard = admin.update_lock.lock().await;
+    let current = current_model_map(&admin).await?;
+    if !current
+        .routes(&section)
+        .is_some_and(|routes| routes.contains_key(model))
+    {
+        return Err(not_found(&section, model));
+    }
+    let (_, model_map) = admin
+        .upstreams
+        .update(|model_map| model_map.routes_mut(&section).unwrap().remove(model))
+        .await
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    info!("Admin API removed {section} model [{model}]");
+    persist(&admin, &model_map).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn current_model_map(admin: &Admin) -> Result<ModelMap, ApiError> {
+    admin.upstreams.model_map().await.ok_or_else(|| {
+        ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No model map has been applied".to_string(),
+        )
+    })
+}
+
+/// Writes `model_map` to the config file, if enabled. The file is replaced
+/// atomically so that it is never reloaded half-written.
+async fn persist(admin: &Admin, model_map: &ModelMap) -> Result<(), ApiError> {
+    let Some(path) = &admin.persist_path else {
+        return Ok(());
+    };
+    let result = async {
+        let yaml = serde_yaml::to_string(&model_map.clone().into_v3())?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, yaml).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    result.await.map_err(|e| {
+        error!("Failed to persist model map to {}: {e:#}", path.display());
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Change was applied but could not be persisted: {e:#}"),
+        )
+    })
+}
+
+fn check_section(section: &str) -> Result<(), ApiError> {
+    if SECTIONS.contains(&section) {
+        return Ok(());
+    }
+    Err(ApiError(
+        StatusCode::NOT_FOUND,
+        format!(
+            "Unknown section {section}, expected one of {}",
+            SECTIONS.join(", ")
+        ),
+    ))
+}
+
+/// Model names can contain `/`, so are matched by a wildcard which may include
+/// the leading `/`.
+fn model_name(model: &str) -> &str {
+    model.strip_prefix('/').unwrap_or(model)
+}
+
+fn not_found(section: &str, model: &str) -> ApiError {
+    ApiError(
+        StatusCode::NOT_FOUND,
+        format!("No {section} model [{model}] is configured"),
+    )
+}
@@ -0,0 +1,1057 @@
+//! Upstream channels and the per-service client maps built on top of them.
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::future::{join_all, BoxFuture};
+use ginepro::{
+    DnsResolver, LoadBalancedChannel, LoadBalancedChannelBuilder, LookupService, ResolutionStrategy,
+    ServiceDefinition,
+};
+use rand::Rng;
+use regex::Regex;
+use tokio::{fs::read, sync::Mutex, time::sleep};
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        http::{self, HeaderMap},
+        Service,
+    },
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint},
+    Request, Status,
+};
+use tower::BoxError;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    model_map::{pattern_regex, validate_v3},
+    pb::{
+        caikit::runtime::{
+            info::info_service_client::InfoServiceClient, nlp::nlp_service_client::NlpServiceClient,
+        },
+        fmaas::generation_service_client::GenerationServiceClient,
+    },
+    rpc::{
+        breaker::{Breakers, CircuitBreaker, CircuitBreakerStatus},
+        cache::ResponseCache,
+        retry::{Retry, TokenBucket},
+    },
+    BatchSplit, CachePolicy, CircuitBreakerPolicy, ConfigErrors, Deadlines, LoadBalancing,
+    MicroBatching, ModelMap, ModelMapV3, ModelRoute, RetryBudget, RetryPolicy, SingleFlightPolicy,
+    Upstream,
+};
+
+use self::balance::{affinity_key, EndpointSet, UpstreamBody, METADATA_NAME_AFFINITY_KEY};
+
+mod balance;
+
+/// Channel to a single upstream, which adds the upstream's static metadata to
+/// every request sent through it.
+#[derive(Debug, Clone)]
+pub struct UpstreamChannel {
+    channel: Transport,
+    metadata: Arc<HeaderMap>,
+    /// Load balancing policy of the model, if its requests are balanced across
+    /// the endpoints of the upstream by the router.
+    load_balancing: Option<Arc<LoadBalancing>>,
+}
+
+/// Underlying channel of an upstream. Upstreams given by hostname are load
+/// balanced across the addresses it resolves to, either by the channel or by the
+/// router, whereas IP addresses and Unix domain sockets are connected to directly.
+#[derive(Debug, Clone)]
+enum Transport {
+    Balanced(LoadBalancedChannel),
+    Endpoints(Arc<EndpointSet>),
+    Direct(Channel),
+}
+
+impl Service<http::Request<BoxBody>> for UpstreamChannel {
+    type Response = http::Response<UpstreamBody>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        match &mut self.channel {
+            Transport::Balanced(channel) => channel.poll_ready(cx).map_err(Into::into),
+            // Endpoints are only picked when the request is sent
+            Transport::Endpoints(_) => Poll::Ready(Ok(())),
+            Transport::Direct(channel) => channel.poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        let key = request
+            .headers_mut()
+            .remove(METADATA_NAME_AFFINITY_KEY)
+            .and_then(|key| key.to_str().ok()?.parse().ok());
+        for (name, value) in self.metadata.iter() {
+            request.headers_mut().insert(name, value.clone());
+        }
+        let response = match &mut self.channel {
+            Transport::Balanced(channel) => channel.call(request),
+            Transport::Endpoints(endpoints) => {
+                return endpoints.call(self.load_balancing.as_deref(), key, request)
+            }
+            Transport::Direct(channel) => channel.call(request),
+        };
+        Box::pin(async move { Ok(response.await?.map(UpstreamBody::new)) })
+    }
+}
+
+/// Generated gRPC clients which can be built for an [`Upstream`].
+pub trait UpstreamClient: Clone {
+    fn build(channel: UpstreamChannel, max_message_size: Option<usize>) -> Self;
+}
+
+macro_rules! impl_upstream_client {
+    ($($client:ident),*) => {
+        $(impl UpstreamClient for $client<UpstreamChannel> {
+            fn build(channel: UpstreamChannel, max_message_size: Option<usize>) -> Self {
+                let client = $client::new(channel);
+                match max_message_size {
+                    Some(size) => client
+                        .max_decoding_message_size(size)
+                        .max_encoding_message_size(size),
+                    None => client,
+                }
+            }
+        })*
+    };
+}
+
+impl_upstream_client!(GenerationServiceClient, NlpServiceClient, InfoServiceClient);
+
+/// Upstream client resolved for a requested model id.
+#[derive(Debug, Clone)]
+pub struct Route<C> {
+    /// Model id to send upstream, which differs from the requested id if it is an alias.
+    pub model_id: String,
+    /// Address of the backend the request is sent to.
+    pub backend: Arc<str>,
+    pub client: C,
+    /// Addresses and clients of the available failover backends of the model, in
+    /// the order the request is retried on them if `client` is unavailable.
+    pub failover: Vec<(Arc<str>, C)>,
+    /// Client of the model's shadow upstream, if it has one and it is available.
+    pub shadow: Option<C>,
+    /// Retry policy of the model, if requests for it are retried.
+    pub(crate) retry: Option<Retry>,
+    /// Default deadlines of requests for the model.
+    pub(crate) deadlines: Arc<Deadlines>,
+    /// Margin that client deadlines are shortened by when they are propagated
+    /// upstream.
+    pub(crate) deadline_margin: Duration,
+    /// Circuit breakers of the backends of the model, if it has any.
+    pub(crate) breakers: Arc<Breakers>,
+    /// Load balancing policy of the model, if it has one.
+    pub(crate) load_balancing: Option<Arc<LoadBalancing>>,
+    /// Splitting of large batches for the model, if they are split.
+    pub(crate) batch_split: Option<Arc<BatchSplit>>,
+    /// Micro-batching of single requests for the model, if they are batched.
+    pub(crate) micro_batching: Option<Arc<MicroBatching>>,
+    /// Cache of the responses to requests for the model, if they are cached.
+    pub(crate) cache: Option<Arc<ResponseCache>>,
+    /// De-duplication of identical concurrent requests for the model, if enabled.
+    pub(crate) single_flight: Option<Arc<SingleFlightPolicy>>,
+}
+
+impl<C> Route<C> {
+    /// Adds the affinity key of `request` for the prefix affinity load balancing
+    /// of the model, if it has it, which is taken from the session of the request
+    /// or else the start of its prompt.
+    pub(crate) fn set_affinity_key<T>(
+        &self,
+        request: &mut Request<T>,
+        prompt: impl FnOnce(&T) -> &str,
+    ) {
+        let Some(LoadBalancing::PrefixAffinity(affinity)) = self.load_balancing.as_deref() else {
+            return;
+        };
+        let key = affinity_key(affinity, request.metadata(), prompt(request.get_ref()));
+        request
+            .metadata_mut()
+            .insert(METADATA_NAME_AFFINITY_KEY, key.into());
+    }
+}
+
+/// Upstream clients of a single gRPC service, keyed by model name.
+///
+/// The whole map is replaced at once when the model map is reloaded, so a request
+/// sees either the old or the new set of models, never a mix of both.
+#[derive(Debug)]
+pub struct ModelClients<C> {
+    /// Name of the service, which labels the metrics of circuit breakers.
+    service: &'static str,
+    /// Model map section the clients are configured in.
+    section: &'static str,
+    routes: RwLock<Routes<C>>,
+}
+
+#[derive(Debug)]
+struct Routes<C> {
+    /// Clients by model name and alias, whose model name is sent upstream.
+    exact: HashMap<String, Arc<Backends<C>>>,
+    /// Clients for model id patterns, most specific first.
+    patterns: Vec<(Regex, Arc<Backends<C>>)>,
+    /// Client for model ids which are not otherwise configured.
+    fallback: Option<Arc<Backends<C>>>,
+    /// Budget that the retries of all models are limited by.
+    retry_budget: Arc<TokenBucket>,
+    deadline_margin: Duration,
+    /// Circuit breakers of the backends of all models.
+    breakers: Vec<Arc<CircuitBreaker>>,
+    /// Response caches of all models.
+    caches: Vec<Arc<ResponseCache>>,
+}
+
+/// Clients of the backends of a configured model, which its requests are split
+/// between by weight.
+#[derive(Debug)]
+struct Backends<C> {
+    /// Name the model is configured with, which is also used to label metrics.
+    name: Arc<str>,
+    backends: Vec<BackendTarget<C>>,
+    /// Addresses and clients of the failover backends which are available.
+    failover: Vec<(Arc<str>, C)>,
+    shadow: Option<C>,
+    retry: Option<Arc<RetryPolicy>>,
+    deadlines: Arc<Deadlines>,
+    breakers: Arc<Breakers>,
+    load_balancing: Option<Arc<LoadBalancing>>,
+    batch_split: Option<Arc<BatchSplit>>,
+    micro_batching: Option<Arc<MicroBatching>>,
+    /// Cache of responses, which is kept across reloads unless its policy changes.
+    cache: Option<Arc<ResponseCache>>,
+    single_flight: Option<Arc<SingleFlightPolicy>>,
+    /// Clients of the backends of the model's adapters, by adapter id.
+    adapters: HashMap<String, Arc<Backends<C>>>,
+}
+
+#[derive(Debug)]
+struct BackendTarget<C> {
+    address: Arc<str>,
+    weight: u32,
+    target: Target<C>,
+}
+
+impl<C: Clone> Backends<C> {
+    /// Picks a backend at random in proportion to the weights of those which are
+    /// available, returning its address and client followed by those of the
+    /// available failover backends. Backends whose circuit breaker is open are
+    /// only picked if all of them are.
+    fn pick(&self, model_id: &str) -> Result<Vec<(Arc<str>, C)>, Status> {
+        let ready = |b: &&BackendTarget<C>| b.weight > 0 && matches!(b.target, Target::Ready(_));
+        let closed = |b: &&BackendTarget<C>| {
+            ready(b) && self.breakers.get(&b.address).map_or(true, |b| !b.is_open())
+        };
+        let any_closed = self.backends.iter().any(|b| closed(&b));
+        let available = || {
+            self.backends
+                .iter()
+                .filter(move |b| if any_closed { closed(b) } else { ready(b) })
+        };
+        let total: u32 = available().map(|b| b.weight).sum();
+        let backend = match total {
+            0 => None,
+            _ if self.backends.len() == 1 => Some(&self.backends[0]),
+            _ => {
+                let mut n = rand::thread_rng().gen_range(0..total);
+                available().find(|b| match n.checked_sub(b.weight) {
+                    Some(rest) => {
+                        n = rest;
+                        false
+                    }
+                    None => true,
+                })
+            }
+        };
+        let mut picked = vec![];
+        if let Some(backend) = backend {
+            picked.push((backend.address.clone(), backend.target.client(model_id)?));
+        }
+        picked.extend(self.failover.iter().cloned());
+        if picked.is_empty() {
+            // Report why the first backend which should get requests is unavailable
+            let backend = self.backends.iter().find(|b| b.weight > 0).unwrap();
+            picked.push((backend.address.clone(), backend.target.client(model_id)?));
+        }
+        Ok(picked)
+    }
+}
+
+/// Client of a configured model, or the reason its upstream is unavailable.
+#[derive(Debug, Clone)]
+enum Target<C> {
+    Ready(C),
+    Unavailable(Arc<str>),
+}
+
+impl<C: Clone> Target<C> {
+    fn client(&self, model_id: &str) -> Result<C, Status> {
+        match self {
+            Target::Ready(client) => Ok(client.clone()),
+            Target::Unavailable(reason) => Err(Status::unavailable(format!(
+                "Upstream of model_id {model_id} is unavailable: {reason}"
+            ))),
+        }
+    }
+}
+
+impl<C: Clone> ModelClients<C> {
+    fn new(service: &'static str, section: &'static str) -> Self {
+        Self {
+            service,
+            section,
+            routes: RwLock::new(Routes {
+                exact: HashMap::new(),
+                patterns: vec![],
+                fallback: None,
+                retry_budget: Arc::new(TokenBucket::new(RetryBudget::default())),
+                deadline_margin: Duration::ZERO,
+                breakers: vec![],
+                caches: vec![],
+            }),
+        }
+    }
+
+    /// Resolves the client for `model_id`. Model names and aliases take precedence
+    /// over patterns, and the fallback client is only used if neither match.
+    pub fn route(&self, model_id: &str) -> Result<Route<C>, Status> {
+        self.route_adapter(model_id, None)
+    }
+
+    /// Resolves the client for `adapter_id` of `model_id` as [`Self::route`] does.
+    /// Adapters which aren't routed to backends of their own are sent to those of
+    /// the model.
+    pub fn route_adapter(
+        &self,
+        model_id: &str,
+        adapter_id: Option<&str>,
+    ) -> Result<Route<C>, Status> {
+        let routes = self.routes.read().unwrap();
+        let adapter = |backends: &Arc<Backends<C>>| match adapter_id
+            .and_then(|adapter_id| backends.adapters.get(adapter_id))
+        {
+            Some(adapter) => {
+                debug!(
+                    "Routing adapter {} of model_id {model_id} to its backends",
+                    adapter_id.unwrap()
+                );
+                adapter.clone()
+            }
+            None => backends.clone(),
+        };
+        if let Some(backends) = routes.exact.get(model_id) {
+            return self.pick(backends.name.to_string(), &adapter(backends), &routes);
+        }
+        let backends = match routes
+            .patterns
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(model_id))
+        {
+            Some((_, backends)) => backends,
+            None => {
+                let backends = routes.fallback.as_ref().ok_or_else(|| {
+                    Status::not_found(format!("Unrecognized model_id: {model_id}"))
+                })?;
+                info!(
+                    "Routing unrecognized {} model_id {model_id} to fallback",
+                    self.section
+                );
+                metrics::counter!("fmaas_router_fallback_request_count", "section" => self.section)
+                    .increment(1);
+                backends
+            }
+        };
+        self.pick(model_id.to_string(), &adapter(backends), &routes)
+    }
+
+    fn pick(
+        &self,
+        model_id: String,
+        backends: &Backends<C>,
+        routes: &Routes<C>,
+    ) -> Result<Route<C>, Status> {
+        let mut picked = backends.pick(&model_id)?.into_iter();
+        let (backend, client) = picked.next().unwrap();
+        metrics::counter!(
+            "fmaas_router_backend_request_count",
+            "section" => self.section,
+            "model" => backends.name.to_string(),
+            "backend" => backend.to_string()
+        )
+        .increment(1);
+        Ok(Route {
+            model_id,
+            backend,
+            client,
+            failover: picked.collect(),
+            shadow: backends.shadow.clone(),
+            retry: backends.retry.clone().map(|policy| Retry {
+                policy,
+                budget: routes.retry_budget.clone(),
+            }),
+            deadlines: backends.deadlines.clone(),
+            deadline_margin: routes.deadline_margin,
+            breakers: backends.breakers.clone(),
+            load_balancing: backends.load_balancing.clone(),
+            batch_split: backends.batch_split.clone(),
+            micro_batching: backends.micro_batching.clone(),
+            cache: backends.cache.clone(),
+            single_flight: backends.single_flight.clone(),
+        })
+    }
+
+    /// Circuit breakers of the backends of all models.
+    fn breakers(&self) -> Vec<Arc<CircuitBreaker>> {
+        self.routes.read().unwrap().breakers.clone()
+    }
+
+    /// Circuit breakers and response caches of all models, which are kept when
+    /// the clients are rebuilt.
+    fn state(&self) -> (Vec<Arc<CircuitBreaker>>, Vec<Arc<ResponseCache>>) {
+        let routes = self.routes.read().unwrap();
+        (routes.breakers.clone(), routes.caches.clone())
+    }
+
+    fn store(&self, mut routes: Routes<C>) {
+        let mut current = self.routes.write().unwrap();
+        // Keep the tokens of the retry budget unless it was reconfigured
+        if routes.retry_budget.config() == current.retry_budget.config() {
+            routes.retry_budget = current.retry_budget.clone();
+        }
+        *current = routes;
+    }
+}
+
+/// Initial and maximum delay between attempts to create the channels of
+/// unavailable upstreams.
+const CHANNEL_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const CHANNEL_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait for the hostname of a new upstream to resolve before it is
+/// considered unavailable.
+const RESOLUTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Owns the load-balanced channels to the model servers and the client maps of
+/// each service that are built from them.
+#[derive(Debug)]
+pub struct Upstreams {
+    default_target_port: u16,
+    /// Whether upstreams use TLS unless configured otherwise per model.
+    upstream_tls: bool,
+    /// Base TLS config for upstreams that use TLS, holding the default CA
+    /// certificate and client identity (if any).
+    client_tls: ClientTlsConfig,
+    channels: Mutex<Channels>,
+    pub generation: Arc<ModelClients<GenerationServiceClient<UpstreamChannel>>>,
+    pub nlp: Arc<ModelClients<NlpServiceClient<UpstreamChannel>>>,
+    pub info: Arc<ModelClients<InfoServiceClient<UpstreamChannel>>>,
+}
+
+/// Channels of the current model map, keyed by their channel config so that they
+/// can be reused across reloads and shared by models served from the same upstream.
+#[derive(Debug, Default)]
+struct Channels {
+    model_map: Option<ModelMap>,
+    ready: HashMap<Upstream, Transport>,
+    /// Upstreams whose channel could not be created, with the reason.
+    failed: HashMap<Upstream, Arc<str>>,
+}
+
+impl Channels {
+    fn insert(&mut self, config: Upstream, channel: anyhow::Result<Transport>) {
+        match channel {
+            Ok(channel) => {
+                self.ready.insert(config, channel);
+            }
+            Err(e) => {
+                warn!("Upstream service [{config}] is unavailable, will keep retrying: {e:#}");
+                self.failed.insert(config, format!("{e:#}").into());
+            }
+        }
+    }
+}
+
+impl Upstreams {
+    pub fn new(default_target_port: u16, upstream_tls: bool, client_tls: ClientTlsConfig) -> Self {
+        Self {
+            default_target_port,
+            upstream_tls,
+            client_tls,
+            channels: Mutex::default(),
+            generation: Arc::new(ModelClients::new("generation", "generation")),
+            nlp: Arc::new(ModelClients::new("nlp", "embeddings")),
+            info: Arc::new(ModelClients::new("info", "embeddings")),
+        }
+    }
+
+    /// States of the circuit breakers of the backends of all services.
+    pub(crate) fn circuit_breakers(&self) -> Vec<CircuitBreakerStatus> {
+        [
+            self.generation.breakers(),
+            self.nlp.breakers(),
+            self.info.breakers(),
+        ]
+        .iter()
+        .flatten()
+        .map(|breaker| breaker.status())
+        .collect()
+    }
+
+    /// Updates the client maps of all services to match `model_map`.
+    ///
+    /// Existing channels are reused for upstreams that are still referenced, new
+    /// ones are created for upstreams that were added and channels which are no
+    /// longer referenced are dropped. Models whose channel cannot be created are
+    /// marked as unavailable until [`Upstreams::retry_unavailable`] succeeds in
+    /// creating it. If the model map is invalid the current clients are left
+    /// untouched.
+    pub async fn apply(&self, model_map: &ModelMap) -> anyhow::Result<()> {
+        let mut channels = self.channels.lock().await;
+        self.apply_locked(&mut channels, model_map).await
+    }
+
+    /// Applies `update` to the current model map (converted to the V3 format) and
+    /// then applies the result as [`Upstreams::apply`] does, returning the output of
+    /// `update` along with the updated model map. The updated model map is
+    /// validated as a config would be, and nothing is applied if it is invalid.
+    pub async fn update<T>(
+        &self,
+        update: impl FnOnce(&mut ModelMapV3) -> T,
+    ) -> anyhow::Result<(T, ModelMap)> {
+        let mut channels = self.channels.lock().await;
+        let mut model_map = channels
+            .model_map
+            .clone()
+            .context("No model map has been applied")?
+            .into_v3();
+        let output = update(&mut model_map);
+        let validation = validate_v3(&model_map);
+        if !validation.errors.is_empty() {
+            return Err(ConfigErrors(validation.errors).into());
+        }
+        let model_map = ModelMap::V3(model_map);
+        self.apply_locked(&mut channels, &model_map).await?;
+        Ok((output, model_map))
+    }
+
+    /// The model map that was last applied.
+    pub async fn model_map(&self) -> Option<ModelMap> {
+        self.channels.lock().await.model_map.clone()
+    }
+
+    async fn apply_locked(
+        &self,
+        channels: &mut Channels,
+        model_map: &ModelMap,
+    ) -> anyhow::Result<()> {
+        let empty = HashMap::new();
+        let generation = model_map.generation().unwrap_or(&empty);
+        let embeddings = model_map.embeddings().unwrap_or(&empty);
+        let generation_fallback = model_map.generation_fallback();
+        let embeddings_fallback = model_map.embeddings_fallback();
+        let configs: HashSet<Upstream> = generation
+            .values()
+            .chain(embeddings.values())
+            .chain(generation_fallback)
+            .chain(embeddings_fallback)
+            .flat_map(|route| route.upstreams())
+            .map(|upstream| upstream.channel_config())
+            .collect();
+        let results = join_all(configs.into_iter().map(|config| {
+            let existing = channels.ready.get(&config).cloned();
+            async move {
+                let channel = match existing {
+                    Some(channel) => Ok(channel),
+                    None => self.create_channel(&config).await,
+                };
+                (config, channel)
+            }
+        }))
+        .await;
+        let mut new_channels = Channels {
+            model_map: Some(model_map.clone()),
+            ..Channels::default()
+        };
+        for (config, channel) in results {
+            new_channels.insert(config, channel);
+        }
+
+        self.store_clients(&new_channels)?;
+        *channels = new_channels;
+        Ok(())
+    }
+
+    /// Keeps retrying to create the channels of unavailable upstreams, making their
+    /// models available again as soon as it succeeds. Runs until the process exits.
+    pub async fn retry_unavailable(self: Arc<Self>) {
+        let mut delay = CHANNEL_RETRY_MIN_DELAY;
+        loop {
+            sleep(delay).await;
+            let mut channels = self.channels.lock().await;
+            if channels.failed.is_empty() {
+                delay = CHANNEL_RETRY_MIN_DELAY;
+                continue;
+            }
+            let failed = std::mem::take(&mut channels.failed);
+            let this = &self;
+            let results = join_all(failed.into_keys().map(|config| async move {
+                let channel = this.create_channel(&config).await;
+                (config, channel)
+            }))
+            .await;
+            let mut recovered = false;
+            for (config, channel) in results {
+                if channel.is_ok() {
+                    info!("Upstream service [{config}] is now available");
+                    recovered = true;
+                }
+                channels.insert(config, channel);
+            }
+            if recovered {
+                if let Err(e) = self.store_clients(&channels) {
+                    error!("Failed to update clients of recovered upstreams: {e:#}");
+                }
+            }
+            delay = match channels.failed.is_empty() {
+                true => CHANNEL_RETRY_MIN_DELAY,
+                false => (delay * 2).min(CHANNEL_RETRY_MAX_DELAY),
+            };
+        }
+    }
+
+    /// Builds the client maps of all services for the current model map and
+    /// replaces the existing ones.
+    fn store_clients(&self, channels: &Channels) -> anyhow::Result<()> {
+        let Some(model_map) = &channels.model_map else {
+            return Ok(());
+        };
+        let generation_clients = clients(model_map, &self.generation, channels)?;
+        let nlp_clients = clients(model_map, &self.nlp, channels)?;
+        let info_clients = clients(model_map, &self.info, channels)?;
+        self.generation.store(generation_clients);
+        self.nlp.store(nlp_clients);
+        self.info.store(info_clients);
+        metrics::gauge!("fmaas_router_unavailable_upstream_count")
+            .set(channels.failed.len() as f64);
+        Ok(())
+    }
+
+    async fn create_channel(&self, upstream: &Upstream) -> anyhow::Result<Transport> {
+        info!("Creating channel for upstream service: [{upstream}]");
+        let address = &upstream.address;
+        let hostname = address.hostname.clone();
+        let port = address.port.unwrap_or(self.default_target_port);
+        let timeout = upstream.timeout_ms.map(Duration::from_millis);
+        if address.is_unix() {
+            return unix_channel(hostname, timeout).map(Transport::Direct);
+        }
+
+        let mut tls_config = None;
+        let tls = upstream.tls.or(address.scheme_tls());
+        if tls.unwrap_or(self.upstream_tls) {
+            let mut config = self.client_tls.clone();
+            if let Some(cert_path) = &upstream.ca_cert_path {
+                let cert_pem = read(cert_path)
+                    .await
+                    .context(format!("couldn't load ca cert from {cert_path}"))?;
+                config = config.ca_certificate(Certificate::from_pem(cert_pem));
+            }
+            tls_config = Some(config);
+        }
+
+        // IP addresses don't need resolving, and IPv6 ones aren't valid ginepro
+        // service names, so they get a plain channel
+        if let Ok(ip) = hostname.parse::<IpAddr>() {
+            let server_name = upstream.tls_server_name.as_ref().unwrap_or(&hostname);
+            let tls_config = tls_config.map(|config| config.domain_name(server_name));
+            return ip_channel(SocketAddr::new(ip, port), timeout, tls_config)
+                .context(format!("Channel failed for service {upstream}"))
+                .map(Transport::Direct);
+        }
+
+        // Endpoints balanced by the router each get a plain channel, which verifies
+        // TLS against the service name as ginepro does
+        if upstream.balance_endpoints {
+            let server_name = upstream.tls_server_name.clone().unwrap_or(hostname.clone());
+            let tls_config = tls_config.map(|config| config.domain_name(&server_name));
+            let lookup = ResolveHostname {
+                hostname,
+                dns: DnsResolver::from_system_config().await?,
+            };
+            let definition = ServiceDefinition::from_parts(server_name, port)?;
+            let connect = move |address| ip_channel(address, timeout, tls_config.clone());
+            return EndpointSet::resolve(
+                upstream.to_string(),
+                definition,
+                lookup,
+                RESOLUTION_TIMEOUT,
+                connect,
+            )
+            .await
+            .map(Transport::Endpoints)
+            .context(format!("Channel failed for service {upstream}"));
+        }
+
+        // Build a load-balanced channel given a service name and a port. ginepro uses
+        // the service name for TLS verification, so when it is overridden the service
+        // is defined by that name and the actual hostname is resolved separately.
+        let channel = match &upstream.tls_server_name {
+            Some(server_name) => {
+                let lookup = ResolveHostname {
+                    hostname,
+                    dns: DnsResolver::from_system_config().await?,
+                };
+                let builder = LoadBalancedChannel::builder((server_name.clone(), port))
+                    .lookup_service(lookup);
+                build_channel(builder, timeout, tls_config).await
+            }
+            None => {
+                build_channel(
+                    LoadBalancedChannel::builder((hostname, port)),
+                    timeout,
+                    tls_config,
+                )
+                .await
+            }
+        };
+        channel
+            .map(Transport::Balanced)
+            .context(format!("Channel failed for service {upstream}"))
+    }
+}
+
+fn ip_channel(
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+    tls_config: Option<ClientTlsConfig>,
+) -> anyhow::Result<Channel> {
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let mut endpoint = Endpoint::from_shared(format!("{scheme}://{addr}"))?;
+    if let Some(timeout) = timeout {
+        endpoint = endpoint.timeout(timeout);
+    }
+    if let Some(tls_config) = tls_config {
+        endpoint = endpoint.tls_config(tls_config)?;
+    }
+    Ok(endpoint.connect_lazy())
+}
+
+#[cfg(unix)]
+fn unix_channel(path: String, timeout: Option<Duration>) -> anyhow::Result<Channel> {
+    use tokio::net::UnixStream;
+
+    // The URI is required but unused, connections are made by the connector
+    let mut endpoint = Endpoint::from_static("http://localhost");
+    if let Some(timeout) = timeout {
+        endpoint = endpoint.timeout(timeout);
+    }
+    let path: Arc<str> = path.into();
+    Ok(
+        endpoint.connect_with_connector_lazy(tower::service_fn(move |_: http::Uri| {
+            UnixStream::connect(path.to_string())
+        })),
+    )
+}
+
+#[cfg(not(unix))]
+fn unix_channel(_path: String, _timeout: Option<Duration>) -> anyhow::Result<Channel> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
+
+async fn build_channel<T>(
+    builder: LoadBalancedChannelBuilder<T, (String, u16)>,
+    timeout: Option<Duration>,
+    tls_config: Option<ClientTlsConfig>,
+) -> anyhow::Result<LoadBalancedChannel>
+where
+    T: LookupService + Send + Sync + 'static,
+{
+    let builder = match timeout {
+        Some(timeout) => builder.timeout(timeout),
+        None => builder,
+    };
+    let builder = match tls_config {
+        Some(tls_config) => builder.with_tls(tls_config),
+        None => builder,
+    };
+    // Resolve the hostname up front so that upstreams which don't resolve are
+    // reported as unavailable rather than leaving requests waiting for endpoints
+    builder
+        .resolution_strategy(ResolutionStrategy::Eager {
+            timeout: RESOLUTION_TIMEOUT,
+        })
+        .channel()
+        .await
+}
+
+/// Resolves a fixed hostname in place of the one in the [`ServiceDefinition`].
+struct ResolveHostname {
+    hostname: String,
+    dns: DnsResolver,
+}
+
+#[tonic::async_trait]
+impl LookupService for ResolveHostname {
+    async fn resolve_service_endpoints(
+        &self,
+        definition: &ServiceDefinition,
+    ) -> Result<HashSet<std::net::SocketAddr>, anyhow::Error> {
+        let definition = ServiceDefinition::from_parts(&self.hostname, definition.port())?;
+        self.dns.resolve_service_endpoints(&definition).await
+    }
+}
+
+/// Builds a new client map for `model_clients`, keeping the state of the circuit
+/// breakers and response caches it already has.
+fn clients<C: UpstreamClient>(
+    model_map: &ModelMap,
+    model_clients: &ModelClients<C>,
+    channels: &Channels,
+) -> anyhow::Result<Routes<C>> {
+    let section = model_clients.section;
+    let (previous_breakers, previous_caches) = model_clients.state();
+    let mut registry = Registry {
+        service: model_clients.service,
+        previous_breakers,
+        breakers: vec![],
+        previous_caches,
+        caches: vec![],
+    };
+    let empty = HashMap::new();
+    let mut exact = HashMap::new();
+    let mut patterns = vec![];
+    for (name, route) in model_map.routes(section).unwrap_or(&empty) {
+        // Aliases and patterns share the model's clients, and so its channels
+        let backends: Arc<Backends<C>> =
+            backends(name, route, model_map, section, &mut registry, channels)?;
+        for id in std::iter::once(name).chain(&route.aliases) {
+            if exact.insert(id.clone(), backends.clone()).is_some() {
+                anyhow::bail!("Model id {id} is configured more than once");
+            }
+        }
+        for pattern in &route.patterns {
+            let regex = pattern_regex(pattern)
+                .context(format!("Invalid pattern {pattern} for model {name}"))?;
+            patterns.push((regex, backends.clone()));
+        }
+    }
+    // Longer patterns are assumed to be more specific
+    patterns.sort_by(|(a, _), (b, _)| {
+        b.as_str()
+            .len()
+            .cmp(&a.as_str().len())
+            .then_with(|| a.as_str().cmp(b.as_str()))
+    });
+    let fallback = model_map
+        .fallback(section)
+        .map(|route| {
+            if !route.aliases.is_empty() || !route.patterns.is_empty() {
+                anyhow::bail!("Fallback routes cannot have aliases or patterns");
+            }
+            backends(
+                "fallback",
+                route,
+                model_map,
+                section,
+                &mut registry,
+                channels,
+            )
+        })
+        .transpose()?;
+    Ok(Routes {
+        exact,
+        patterns,
+        fallback,
+        retry_budget: Arc::new(TokenBucket::new(model_map.retry_budget())),
+        deadline_margin: model_map.deadline_margin(),
+        breakers: registry.breakers,
+        caches: registry.caches,
+    })
+}
+
+/// Circuit breakers and response caches of a client map being built, which
+/// reuses those of the previous client map so that their state survives reloads
+/// and other rebuilds, unless their policy changed.
+struct Registry {
+    service: &'static str,
+    previous_breakers: Vec<Arc<CircuitBreaker>>,
+    breakers: Vec<Arc<CircuitBreaker>>,
+    previous_caches: Vec<Arc<ResponseCache>>,
+    caches: Vec<Arc<ResponseCache>>,
+}
+
+impl Registry {
+    fn breaker(
+        &mut self,
+        model: &str,
+        backend: &Arc<str>,
+        policy: &CircuitBreakerPolicy,
+    ) -> Arc<CircuitBreaker> {
+        // Adapters of a model share the breakers of the backends they have in common
+        if let Some(breaker) = self
+            .breakers
+            .iter()
+            .find(|breaker| breaker.is_for(model, backend, policy))
+        {
+            return breaker.clone();
+        }
+        let breaker = match self
+            .previous_breakers
+            .iter()
+            .find(|breaker| breaker.is_for(model, backend, policy))
+        {
+            Some(breaker) => breaker.clone(),
+            None => Arc::new(CircuitBreaker::new(
+                self.service,
+                model.into(),
+                backend.clone(),
+                policy.clone(),
+            )),
+        };
+        self.breakers.push(breaker.clone());
+        breaker
+    }
+
+    fn cache(&mut self, model: &str, policy: &CachePolicy) -> Arc<ResponseCache> {
+        // Adapters of a model share its cache, since their requests differ anyway
+        if let Some(cache) = self.caches.iter().find(|cache| cache.is_for(model, policy)) {
+            return cache.clone();
+        }
+        let cache = match self
+            .previous_caches
+            .iter()
+            .find(|cache| cache.is_for(model, policy))
+        {
+            Some(cache) => cache.clone(),
+            None => Arc::new(ResponseCache::new(
+                self.service,
+                model.into(),
+                policy.clone(),
+            )),
+        };
+        self.caches.push(cache.clone());
+        cache
+    }
+}
+
+fn backends<C: UpstreamClient>(
+    name: &str,
+    route: &ModelRoute,
+    model_map: &ModelMap,
+    section: &str,
+    registry: &mut Registry,
+    channels: &Channels,
+) -> anyhow::Result<Arc<Backends<C>>> {
+    let adapters = route
+        .adapters
+        .keys()
+        .map(|adapter| {
+            let route = route.adapter_route(adapter).unwrap();
+            let backends = backends(name, &route, model_map, section, registry, channels)?;
+            Ok((adapter.clone(), backends))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let cache = route
+        .cache
+        .as_ref()
+        .map(|policy| registry.cache(name, policy));
+    let metadata = route
+        .upstream
+        .metadata_headers()
+        .context(format!("Invalid metadata for model {name}"))?;
+    let metadata = Arc::new(metadata);
+    let load_balancing = route.load_balancing.clone().map(Arc::new);
+    let build = |upstream: &Upstream| {
+        channels
+            .ready
+            .get(&upstream.channel_config())
+            .map(|channel| {
+                let channel = UpstreamChannel {
+                    channel: channel.clone(),
+                    metadata: metadata.clone(),
+                    load_balancing: load_balancing.clone(),
+                };
+                C::build(channel, upstream.max_message_size)
+            })
+    };
+    let backends = route
+        .backend_upstreams()
+        .into_iter()
+        .map(|(upstream, weight)| {
+            let target = match build(&upstream) {
+                Some(client) => Target::Ready(client),
+                None => Target::Unavailable(channels.failed[&upstream.channel_config()].clone()),
+            };
+            BackendTarget {
+                address: upstream.address.to_string().into(),
+                weight,
+                target,
+            }
+        })
+        .collect();
+    let failover = route
+        .failover_upstreams()
+        .into_iter()
+        .filter_map(|upstream| Some((upstream.address.to_string().into(), build(&upstream)?)))
+        .collect();
+    // Requests are only shadowed while the shadow upstream is available
+    let shadow = route
+        .shadow_upstream()
+        .and_then(|upstream| build(&upstream));
+    let breaker_policy = route
+        .circuit_breaker
+        .as_ref()
+        .or(model_map.circuit_breaker_policy(section));
+    let breakers = match breaker_policy {
+        Some(policy) => route
+            .backend_upstreams()
+            .into_iter()
+            .map(|(upstream, _)| upstream)
+            .chain(route.failover_upstreams())
+            .map(|upstream| {
+                let address: Arc<str> = upstream.address.to_string().into();
+                let breaker = registry.breaker(name, &address, policy);
+                (address, breaker)
+            })
+            .collect(),
+        None => Breakers::new(),
+    };
+    Ok(Arc::new(Backends {
+        name: name.into(),
+        backends,
+        failover,
+        shadow,
+        retry: route
+            .retry
+            .as_ref()
+            .or(model_map.retry_policy(section))
+            .cloned()
+            .map(Arc::new),
+        deadlines: Arc::new(
+            route
+                .deadline
+                .clone()
+                .unwrap_or_default()
+                .or(model_map.deadlines(section)),
+        ),
+        breakers: Arc::new(breakers),
+        load_balancing,
+        batch_split: route.batch_split.clone().map(Arc::new),
+        micro_batching: route.micro_batching.clone().map(Arc::new),
+        cache,
+        single_flight: route.single_flight.clone().map(Arc::new),
+        adapters,
+    }))
+}
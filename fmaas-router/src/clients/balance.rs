@@ -0,0 +1,501 @@
+//! Balancing of requests across the endpoints that the hostname of an upstream
+//! resolves to, for models whose [`LoadBalancing`] policy needs to know which
+//! endpoint each request is sent to.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock, Weak,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use futures::future::{ready, BoxFuture};
+use ginepro::{LookupService, ServiceDefinition};
+use rand::Rng;
+use tonic::{
+    codegen::{
+        http::{self, HeaderMap, HeaderValue},
+        Body as HttpBody, Bytes,
+    },
+    metadata::MetadataMap,
+    transport::{Body, Channel},
+    Status,
+};
+use tower::{BoxError, ServiceExt};
+use tracing::{info, warn};
+
+use crate::{LoadBalancing, PrefixAffinity};
+
+/// Name of the metadata holding the affinity key of a request, which is added by
+/// the servicers and removed before the request is sent upstream.
+pub(super) const METADATA_NAME_AFFINITY_KEY: &str = "fmaas-router-affinity-key";
+
+/// How often the hostname of an upstream is resolved again, as ginepro does.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of points of each endpoint on the hash ring, which evens out the share
+/// of keys that each endpoint gets.
+const RING_POINTS: u64 = 100;
+
+/// Affinity key of a request for `affinity`, which is the hash of its session if
+/// it has one in `metadata`, or else of the start of its `prompt`.
+pub(super) fn affinity_key(affinity: &PrefixAffinity, metadata: &MetadataMap, prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match metadata.get(&affinity.session_header) {
+        Some(session) => session.as_bytes().hash(&mut hasher),
+        None => {
+            let end = prompt
+                .char_indices()
+                .nth(affinity.prefix_length)
+                .map_or(prompt.len(), |(i, _)| i);
+            prompt[..end].hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Endpoints of an upstream, which are kept up to date by resolving its hostname
+/// periodically for as long as the set is in use.
+#[derive(Debug)]
+pub(super) struct EndpointSet {
+    upstream: String,
+    endpoints: RwLock<Arc<Endpoints>>,
+}
+
+/// Endpoints along with the hash ring that affinity keys are mapped onto them by.
+#[derive(Debug, Default)]
+struct Endpoints {
+    endpoints: Vec<Arc<Endpoint>>,
+    /// Points of the endpoints on the ring by hash, with their index.
+    ring: Vec<(u64, usize)>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    upstream: Arc<str>,
+    address: SocketAddr,
+    channel: Channel,
+    in_flight: AtomicUsize,
+}
+
+impl EndpointSet {
+    /// Resolves the endpoints of `upstream` with `lookup`, failing if it doesn't
+    /// resolve within `timeout`, and keeps resolving them in the background.
+    /// Channels to new endpoints are created with `connect`.
+    pub async fn resolve<L, F>(
+        upstream: String,
+        definition: ServiceDefinition,
+        lookup: L,
+        timeout: Duration,
+        connect: F,
+    ) -> anyhow::Result<Arc<Self>>
+    where
+        L: LookupService + Send + Sync + 'static,
+        F: Fn(SocketAddr) -> anyhow::Result<Channel> + Send + Sync + 'static,
+    {
+        let addresses =
+            tokio::time::timeout(timeout, lookup.resolve_service_endpoints(&definition))
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out resolving endpoints"))??;
+        let set = Arc::new(Self {
+            upstream,
+            endpoints: RwLock::default(),
+        });
+        set.update(addresses, &connect);
+        tokio::spawn(refresh(Arc::downgrade(&set), definition, lookup, connect));
+        Ok(set)
+    }
+
+    /// Replaces the endpoints with those at `addresses`, keeping the channels and
+    /// requests in flight of those which are still there.
+    fn update(
+        &self,
+        addresses: HashSet<SocketAddr>,
+        connect: &impl Fn(SocketAddr) -> anyhow::Result<Channel>,
+    ) {
+        let current = self.endpoints.read().unwrap().clone();
+        let mut existing: HashMap<_, _> = current
+            .endpoints
+            .iter()
+            .map(|endpoint| (endpoint.address, endpoint.clone()))
+            .collect();
+        let mut addresses: Vec<_> = addresses.into_iter().collect();
+        addresses.sort();
+        let unchanged = addresses.len() == existing.len()
+            && addresses
+                .iter()
+                .all(|address| existing.contains_key(address));
+        if unchanged {
+            return;
+        }
+        let endpoints: Vec<_> = addresses
+            .into_iter()
+            .filter_map(|address| match existing.remove(&address) {
+                Some(endpoint) => Some(endpoint),
+                None => match connect(address) {
+                    Ok(channel) => Some(Arc::new(Endpoint {
+                        upstream: self.upstream.as_str().into(),
+                        address,
+                        channel,
+                        in_flight: AtomicUsize::new(0),
+                    })),
+                    Err(e) => {
+                        warn!(
+                            "Failed to create channel for endpoint {address} of upstream service [{}]: {e:#}",
+                            self.upstream
+                        );
+                        None
+                    }
+                },
+            })
+            .collect();
+        info!(
+            "Upstream service [{}] now has endpoints {:?}",
+            self.upstream,
+            endpoints.iter().map(|e| e.address).collect::<Vec<_>>()
+        );
+        let mut ring: Vec<_> = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(i, endpoint)| {
+                (0..RING_POINTS).map(move |point| {
+                    let mut hasher = DefaultHasher::new();
+                    (endpoint.address, point).hash(&mut hasher);
+                    (hasher.finish(), i)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+        *self.endpoints.write().unwrap() = Arc::new(Endpoints { endpoints, ring });
+    }
+
+    /// Sends `request` to the endpoint picked for it by `balancing`, according to
+    /// its affinity `key` (if any).
+    pub fn call(
+        &self,
+        balancing: Option<&LoadBalancing>,
+        key: Option<u64>,
+        request: http::Request<tonic::body::BoxBody>,
+    ) -> BoxFuture<'static, Result<http::Response<UpstreamBody>, BoxError>> {
+        let endpoint = match self.pick(balancing, key) {
+            Ok(endpoint) => endpoint,
+            Err(status) => return Box::pin(ready(Err(status.into()))),
+        };
+        let in_flight = InFlight::new(endpoint.clone());
+        let channel = endpoint.channel.clone();
+        Box::pin(async move {
+            let response = channel.oneshot(request).await?;
+            Ok(response.map(|body| UpstreamBody {
+                body,
+                in_flight: Some(in_flight),
+            }))
+        })
+    }
+
+    fn pick(
+        &self,
+        balancing: Option<&LoadBalancing>,
+        key: Option<u64>,
+    ) -> Result<Arc<Endpoint>, Status> {
+        let endpoints = self.endpoints.read().unwrap().clone();
+        let all = &endpoints.endpoints;
+        if all.is_empty() {
+            return Err(Status::unavailable(format!(
+                "No endpoints are available for upstream service [{}]",
+                self.upstream
+            )));
+        }
+        let endpoint = match (balancing, key) {
+            (Some(LoadBalancing::PrefixAffinity(affinity)), Some(key)) => {
+                endpoints.bounded_load(affinity.load_factor, key)
+            }
+            // Requests without an affinity key are balanced by their load too
+            _ => endpoints.least_requests(),
+        };
+        Ok(endpoint.clone())
+    }
+}
+
+impl Endpoints {
+    /// Whichever of two endpoints picked at random has fewer requests in flight.
+    fn least_requests(&self) -> &Arc<Endpoint> {
+        let mut rng = rand::thread_rng();
+        let n = self.endpoints.len();
+        let i = rng.gen_range(0..n);
+        if n == 1 {
+            return &self.endpoints[i];
+        }
+        // The second choice is always a different endpoint
+        let j = (i + rng.gen_range(1..n)) % n;
+        let (first, second) = (&self.endpoints[i], &self.endpoints[j]);
+        match second.in_flight.load(Ordering::Relaxed) < first.in_flight.load(Ordering::Relaxed) {
+            true => second,
+            false => first,
+        }
+    }
+
+    /// Endpoint of `key` on the ring, skipping those which have more than
+    /// `load_factor` times the average number of requests in flight.
+    fn bounded_load(&self, load_factor: f64, key: u64) -> &Arc<Endpoint> {
+        let total: usize = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.in_flight.load(Ordering::Relaxed))
+            .sum();
+        // Counting this request, so that the capacity is at least 1
+        let capacity = (load_factor * (total + 1) as f64 / self.endpoints.len() as f64).ceil();
+        let start = self.ring.partition_point(|(hash, _)| *hash < key);
+        let (before, after) = self.ring.split_at(start);
+        after
+            .iter()
+            .chain(before)
+            .map(|(_, i)| &self.endpoints[*i])
+            .find(|endpoint| (endpoint.in_flight.load(Ordering::Relaxed) as f64) < capacity)
+            .unwrap_or(&self.endpoints[self.ring[start % self.ring.len()].1])
+    }
+}
+
+/// Keeps the endpoints of `set` up to date until it is dropped.
+async fn refresh<L, F>(set: Weak<EndpointSet>, definition: ServiceDefinition, lookup: L, connect: F)
+where
+    L: LookupService,
+    F: Fn(SocketAddr) -> anyhow::Result<Channel>,
+{
+    loop {
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        let Some(set) = set.upgrade() else {
+            return;
+        };
+        match lookup.resolve_service_endpoints(&definition).await {
+            Ok(addresses) => set.update(addresses, &connect),
+            Err(e) => warn!(
+                "Failed to resolve endpoints of upstream service [{}], keeping the current ones: {e:#}",
+                set.upstream
+            ),
+        }
+    }
+}
+
+/// A request in flight to an endpoint, which is counted until it is dropped.
+#[derive(Debug)]
+struct InFlight(Arc<Endpoint>);
+
+impl InFlight {
+    fn new(endpoint: Arc<Endpoint>) -> Self {
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        endpoint.gauge().increment(1.0);
+        Self(endpoint)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.gauge().decrement(1.0);
+    }
+}
+
+impl Endpoint {
+    fn gauge(&self) -> metrics::Gauge {
+        metrics::gauge!(
+            "fmaas_router_endpoint_in_flight_requests",
+            "upstream" => self.upstream.to_string(),
+            "endpoint" => self.address.to_string()
+        )
+    }
+}
+
+/// Body of a response from an upstream, which keeps the request counted as in
+/// flight to its endpoint until the whole response has been received, so that
+/// open streams count too.
+#[derive(Debug)]
+pub struct UpstreamBody {
+    body: Body,
+    in_flight: Option<InFlight>,
+}
+
+impl UpstreamBody {
+    pub(super) fn new(body: Body) -> Self {
+        Self {
+            body,
+            in_flight: None,
+        }
+    }
+}
+
+impl HttpBody for UpstreamBody {
+    type Data = Bytes;
+    type Error = <Body as HttpBody>::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap<HeaderValue>>, Self::Error>> {
+        let this = self.get_mut();
+        let trailers = Pin::new(&mut this.body).poll_trailers(cx);
+        if trailers.is_ready() {
+            this.in_flight = None;
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i as u8], 8033))
+    }
+
+    fn connect(address: SocketAddr) -> anyhow::Result<Channel> {
+        Ok(Channel::from_shared(format!("http://{address}"))?.connect_lazy())
+    }
+
+    fn endpoint_set(n: usize) -> EndpointSet {
+        let set = EndpointSet {
+            upstream: "upstream".to_string(),
+            endpoints: RwLock::default(),
+        };
+        set.update((0..n).map(address).collect(), &connect);
+        set
+    }
+
+    fn affinity() -> LoadBalancing {
+        LoadBalancing::PrefixAffinity(PrefixAffinity::default())
+    }
+
+    fn pick(set: &EndpointSet, balancing: &LoadBalancing, key: u64) -> SocketAddr {
+        set.pick(Some(balancing), Some(key)).unwrap().address
+    }
+
+    #[tokio::test]
+    async fn ring_maps_keys_consistently() {
+        let set = endpoint_set(3);
+        let endpoints = set.endpoints.read().unwrap().clone();
+        assert_eq!(endpoints.ring.len(), 3 * RING_POINTS as usize);
+        assert!(endpoints.ring.windows(2).all(|w| w[0] <= w[1]));
+
+        let balancing = affinity();
+        let keys: Vec<u64> = (0..1000u64)
+            .map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .collect();
+        let picked: Vec<_> = keys
+            .iter()
+            .map(|&key| pick(&set, &balancing, key))
+            .collect();
+        for i in 0..3 {
+            let share = picked.iter().filter(|&&a| a == address(i)).count();
+            assert!(share > 200, "endpoint {i} got {share} of 1000 keys");
+        }
+        for (&key, &address) in keys.iter().zip(&picked) {
+            assert_eq!(pick(&set, &balancing, key), address);
+        }
+
+        // Only the keys of a removed endpoint move to other endpoints
+        set.update([address(0), address(2)].into(), &connect);
+        for (&key, &before) in keys.iter().zip(&picked) {
+            let after = pick(&set, &balancing, key);
+            match before == address(1) {
+                true => assert_ne!(after, address(1)),
+                false => assert_eq!(after, before),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ring_skips_overloaded_endpoints() {
+        let set = endpoint_set(3);
+        let balancing = affinity();
+        let key = 42;
+        let first = set.pick(Some(&balancing), Some(key)).unwrap();
+        let in_flight: Vec<_> = (0..4).map(|_| InFlight::new(first.clone())).collect();
+        // Counting the next request, the capacity is ceil(1.25 * 5 / 3) = 3
+        let second = pick(&set, &balancing, key);
+        assert_ne!(second, first.address);
+        drop(in_flight);
+        assert_eq!(pick(&set, &balancing, key), first.address);
+    }
+
+    #[tokio::test]
+    async fn update_keeps_existing_endpoints() {
+        let set = endpoint_set(2);
+        let before = set.endpoints.read().unwrap().clone();
+        let _in_flight = InFlight::new(before.endpoints[0].clone());
+        set.update((0..3).map(address).collect(), &connect);
+        let after = set.endpoints.read().unwrap().clone();
+        assert_eq!(after.endpoints.len(), 3);
+        assert!(Arc::ptr_eq(&before.endpoints[0], &after.endpoints[0]));
+        assert_eq!(after.endpoints[0].in_flight.load(Ordering::Relaxed), 1);
+
+        // An unchanged set of addresses leaves the endpoints as they are
+        set.update((0..3).map(address).collect(), &connect);
+        assert!(Arc::ptr_eq(&after, &set.endpoints.read().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn least_requests_avoids_busiest_endpoint() {
+        let set = endpoint_set(3);
+        let balancing = LoadBalancing::LeastRequests {};
+        let busiest = set.pick(Some(&balancing), None).unwrap();
+        let _in_flight = InFlight::new(busiest.clone());
+        // The busiest endpoint is always compared with another, less busy one
+        for _ in 0..100 {
+            assert_ne!(
+                set.pick(Some(&balancing), None).unwrap().address,
+                busiest.address
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn least_requests_spreads_requests() {
+        let set = endpoint_set(3);
+        let balancing = LoadBalancing::LeastRequests {};
+        let in_flight: Vec<_> = (0..30)
+            .map(|_| InFlight::new(set.pick(Some(&balancing), None).unwrap()))
+            .collect();
+        let endpoints = set.endpoints.read().unwrap().clone();
+        for endpoint in &endpoints.endpoints {
+            let count = endpoint.in_flight.load(Ordering::Relaxed);
+            // Ties are broken at random, so the counts are only roughly even
+            assert!((5..=15).contains(&count), "{}: {count}", endpoint.address);
+        }
+        drop(in_flight);
+        assert!(endpoints
+            .endpoints
+            .iter()
+            .all(|endpoint| endpoint.in_flight.load(Ordering::Relaxed) == 0));
+    }
+
+    #[tokio::test]
+    async fn least_requests_with_one_endpoint() {
+        let set = endpoint_set(1);
+        let endpoint = set.pick(None, None).unwrap();
+        let _in_flight = InFlight::new(endpoint.clone());
+        assert_eq!(set.pick(None, None).unwrap().address, endpoint.address);
+    }
+
+    #[tokio::test]
+    async fn no_endpoints_is_unavailable() {
+        let set = endpoint_set(0);
+        let status = set.pick(None, None).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
@@ -1,12 +1,18 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
 
-use clap::Parser;
-use fmaas_router::{server, tracing_utils::init_logging, ModelMap};
+use clap::{Parser, Subcommand};
+use fmaas_router::{server, tracing_utils::init_logging, validate_path, ModelMap};
 
 /// App Configuration
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(default_value = "8033", long, short, env)]
     grpc_port: u16,
     #[clap(default_value = "3000", long, short, env)]
@@ -15,8 +21,20 @@ struct Args {
     default_upstream_port: u16,
     #[clap(long, env)]
     json_output: bool,
+    /// Model map config file, or a directory of model map files which are merged
+    #[clap(long, env, required = true)]
+    model_map_config: Option<String>,
+    /// How often to check the model map config for changes, in seconds (0 to only reload on SIGHUP)
+    #[clap(default_value = "10", long, env)]
+    model_map_reload_interval: u64,
+    /// Bearer token required by the admin API on the probe port, which is only
+    /// enabled if this is set
     #[clap(long, env)]
-    model_map_config: String,
+    admin_token: Option<String>,
+    /// Write changes made through the admin API back to the model map config. Any
+    /// interpolated environment variables and files are written as their values.
+    #[clap(long, env)]
+    admin_persist: bool,
     #[clap(long, env)]
     tls_cert_path: Option<String>,
     #[clap(long, env)]
@@ -33,19 +51,39 @@ struct Args {
     otlp_service_name: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a model map config, reporting all errors and warnings in it
+    ValidateConfig {
+        /// Path of the model map config file or directory
+        path: String,
+    },
+}
+
 fn main() -> Result<(), std::io::Error> {
     //Get args
     let args = Args::parse();
 
+    if let Some(Command::ValidateConfig { path }) = args.command {
+        validate_config(&path);
+    }
+    let model_map_config = args.model_map_config.unwrap();
+
     if args.tls_key_path.is_some() != args.tls_cert_path.is_some() {
         panic!("tls: must provide both cert and key")
     }
     if args.tls_client_ca_cert_path.is_some() && args.tls_cert_path.is_none() {
         panic!("tls: cannot provide client ca cert without keypair")
     }
+    if args.admin_persist && Path::new(&model_map_config).is_dir() {
+        panic!("admin: cannot persist changes to a model map directory")
+    }
 
     // Load model map config
-    let model_map = ModelMap::load(args.model_map_config);
+    let model_map = ModelMap::load(&model_map_config).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    });
 
     // Launch Tokio runtime
     tokio::runtime::Builder::new_multi_thread()
@@ -67,10 +105,40 @@ fn main() -> Result<(), std::io::Error> {
                 args.default_upstream_port,
                 args.upstream_tls,
                 args.upstream_tls_ca_cert_path,
+                model_map_config.into(),
+                (args.model_map_reload_interval > 0)
+                    .then(|| Duration::from_secs(args.model_map_reload_interval)),
                 model_map,
+                args.admin_token,
+                args.admin_persist,
             )
             .await;
 
             Ok(())
         })
 }
+
+/// Validates the model map config at `path`, printing all problems found in it
+/// and exiting with a non-zero status if it has any errors.
+fn validate_config(path: &str) -> ! {
+    let validation = validate_path(path).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    });
+    for warning in &validation.warnings {
+        eprintln!("warning: {warning}");
+    }
+    for error in &validation.errors {
+        eprintln!("error: {error}");
+    }
+    if !validation.errors.is_empty() {
+        eprintln!(
+            "{path}: {} errors, {} warnings",
+            validation.errors.len(),
+            validation.warnings.len()
+        );
+        std::process::exit(1);
+    }
+    println!("{path}: OK ({} warnings)", validation.warnings.len());
+    std::process::exit(0);
+}
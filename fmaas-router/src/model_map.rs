@@ -0,0 +1,1116 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::Ipv6Addr,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use regex::Regex;
+use serde::{de::MapAccess, de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use tonic::codegen::http::{HeaderMap, HeaderName, HeaderValue};
+
+pub(crate) use self::validation::validate_v3;
+pub use self::{
+    balance::{LoadBalancing, PrefixAffinity},
+    batching::{BatchSplit, MicroBatching, SplitFailure},
+    breaker::{CircuitBreakerPolicy, CircuitBreakerSettings},
+    cache::{CachePolicy, SingleFlightPolicy},
+    deadline::{DeadlineSettings, Deadlines},
+    retry::{RetryBudget, RetryPolicy, RetrySettings},
+    validation::{validate, validate_path, ConfigErrors, ConfigIssue, Validation},
+};
+
+mod balance;
+mod batching;
+mod breaker;
+mod cache;
+mod deadline;
+mod dir;
+mod interpolate;
+mod retry;
+mod validation;
+
+/// Sections of the model map holding the routes of each kind of model.
+pub const SECTIONS: [&str; 2] = ["generation", "embeddings"];
+
+/// Address of an upstream service, given as `host[:port]`, `[ipv6][:port]` or a
+/// URI such as `grpcs://host:port` or `unix:///path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct ServiceAddr {
+    /// Hostname or IP address (without brackets), or the socket path of a Unix
+    /// domain socket address.
+    pub hostname: String,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub scheme: Option<Scheme>,
+}
+
+/// URI scheme an upstream address was given with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    /// `grpc://`, plaintext
+    Grpc,
+    /// `grpcs://`, TLS
+    Grpcs,
+    /// `unix://`, plaintext over a Unix domain socket
+    Unix,
+}
+
+impl ServiceAddr {
+    /// Whether TLS is implied by the scheme of the address.
+    pub fn scheme_tls(&self) -> Option<bool> {
+        self.scheme.map(|scheme| scheme == Scheme::Grpcs)
+    }
+
+    pub fn is_unix(&self) -> bool {
+        self.scheme == Some(Scheme::Unix)
+    }
+}
+
+impl FromStr for ServiceAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some(("grpc", rest)) => (Some(Scheme::Grpc), rest),
+            Some(("grpcs", rest)) => (Some(Scheme::Grpcs), rest),
+            Some(("unix", path)) => {
+                if path.is_empty() {
+                    return Err(format!(
+                        "Missing socket path in configured service name: {s:?}"
+                    ));
+                }
+                return Ok(Self {
+                    hostname: path.to_string(),
+                    port: None,
+                    scheme: Some(Scheme::Unix),
+                });
+            }
+            Some((scheme, _)) => {
+                return Err(format!(
+                    "Unsupported scheme in configured service name: {scheme}"
+                ))
+            }
+            None => (None, s),
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (hostname, port) = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let (ip, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("Unclosed [ in configured service name: {s:?}"))?;
+                ip.parse::<Ipv6Addr>().map_err(|_| {
+                    format!("Invalid IPv6 address in configured service name: {ip}")
+                })?;
+                match port {
+                    "" => (ip, None),
+                    port => match port.strip_prefix(':') {
+                        Some(port) => (ip, Some(port)),
+                        None => return Err(format!("Invalid configured service name: {s:?}")),
+                    },
+                }
+            }
+            None => match rest.split_once(':') {
+                Some((_, port)) if port.contains(':') => {
+                    return Err(format!(
+                        "Configured service name contains more than one : character \
+                        (IPv6 addresses must be enclosed in []): {s:?}"
+                    ))
+                }
+                Some((hostname, port)) => (hostname, Some(port)),
+                None => (rest, None),
+            },
+        };
+        if hostname.is_empty() {
+            return Err(format!(
+                "Missing hostname in configured service name: {s:?}"
+            ));
+        }
+        let port = port
+            .map(|p| {
+                p.parse::<u16>()
+                    .map_err(|_| format!("Invalid port in configured service name: {p}"))
+            })
+            .transpose()?;
+        Ok(Self {
+            hostname: hostname.to_string(),
+            port,
+            scheme,
+        })
+    }
+}
+
+/// Serialized in the same string form it is configured with.
+impl Serialize for ServiceAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for ServiceAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scheme {
+            Some(Scheme::Unix) => return write!(f, "unix://{}", self.hostname),
+            Some(Scheme::Grpc) => f.write_str("grpc://")?,
+            Some(Scheme::Grpcs) => f.write_str("grpcs://")?,
+            None => {}
+        }
+        if self.hostname.contains(':') {
+            write!(f, "[{}]", self.hostname)?;
+        } else {
+            f.write_str(&self.hostname)?;
+        }
+        match self.port {
+            Some(port) => write!(f, ":{port}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Upstream service of a model, along with optional per-model connection settings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub address: ServiceAddr,
+    /// Enables or disables TLS for this upstream, overriding `--upstream-tls` and
+    /// the scheme of the address.
+    pub tls: Option<bool>,
+    /// CA certificate used to verify this upstream, overriding `--upstream-tls-ca-cert-path`.
+    pub ca_cert_path: Option<String>,
+    /// Server name to use for SNI and certificate verification instead of the hostname.
+    pub tls_server_name: Option<String>,
+    /// Timeout applied to each request sent to this upstream.
+    pub timeout_ms: Option<u64>,
+    /// Maximum size of request and response messages exchanged with this upstream.
+    pub max_message_size: Option<usize>,
+    /// Static metadata added to every request sent to this upstream.
+    pub metadata: BTreeMap<String, String>,
+    /// Whether requests are balanced across the endpoints the hostname of this
+    /// upstream resolves to by the router rather than by its channel, which is
+    /// the case for the upstreams of models with a [`LoadBalancing`] policy.
+    pub balance_endpoints: bool,
+}
+
+impl Upstream {
+    fn new(address: ServiceAddr) -> Self {
+        Self {
+            address,
+            tls: None,
+            ca_cert_path: None,
+            tls_server_name: None,
+            timeout_ms: None,
+            max_message_size: None,
+            metadata: BTreeMap::new(),
+            balance_endpoints: false,
+        }
+    }
+
+    /// The subset of settings which apply to the channel rather than the clients
+    /// built on top of it, used to share channels between upstreams.
+    pub(crate) fn channel_config(&self) -> Self {
+        Self {
+            max_message_size: None,
+            metadata: BTreeMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Converts the static metadata of this upstream into request headers.
+    pub(crate) fn metadata_headers(&self) -> anyhow::Result<HeaderMap> {
+        self.metadata
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    HeaderName::try_from(k).context(format!("Invalid metadata key {k}"))?,
+                    HeaderValue::try_from(v).context(format!("Invalid metadata value for {k}"))?,
+                ))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.address.fmt(f)
+    }
+}
+
+/// One of several upstreams that the traffic of a model is split between, e.g.
+/// to send a small share of it to a canary deployment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Backend {
+    pub address: ServiceAddr,
+    /// Share of the model's requests sent to this backend, relative to the
+    /// weights of its other backends.
+    pub weight: u32,
+}
+
+/// Deserialized from either an address string, with a weight of 1, or a mapping
+/// with an `address` and a `weight`.
+impl<'de> Deserialize<'de> for Backend {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct BackendFields {
+            #[serde(deserialize_with = "service_addr_from_str")]
+            address: ServiceAddr,
+            #[serde(default = "default_weight")]
+            weight: u32,
+        }
+
+        struct BackendVisitor;
+
+        impl<'de> Visitor<'de> for BackendVisitor {
+            type Value = Backend;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a service address string or a backend mapping")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Backend, E> {
+                let address =
+                    service_addr_from_str(serde::de::value::StrDeserializer::<E>::new(s))?;
+                Ok(Backend {
+                    address,
+                    weight: default_weight(),
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Backend, A::Error> {
+                let fields =
+                    BackendFields::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(Backend {
+                    address: fields.address,
+                    weight: fields.weight,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(BackendVisitor)
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Route of a model to its upstream, along with any other model ids which are
+/// routed the same way.
+///
+/// In the V3 format this can be given either as a `"host:port"` string or as a
+/// mapping with an `address` (or weighted `backends`) and any of the other fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelRoute {
+    /// Upstream of the model. If its traffic is split between backends, these are
+    /// the settings shared by all of them and the address is the first backend's.
+    pub upstream: Upstream,
+    /// Backends that the requests for the model are split between by weight, or
+    /// empty if they are all sent to `upstream`.
+    pub backends: Vec<Backend>,
+    /// Upstreams that requests are retried on in order when the backend they were
+    /// sent to is unavailable. They share the settings of `upstream`.
+    pub failover: Vec<ServiceAddr>,
+    /// Upstream that a copy of the model's requests is sent to, whose responses
+    /// are compared with those of the model's backends but not returned. It
+    /// shares the settings of `upstream`.
+    pub shadow: Option<ServiceAddr>,
+    /// Retry policy of idempotent requests for the model, overriding that of its
+    /// section.
+    pub retry: Option<RetryPolicy>,
+    /// Default deadlines of requests for the model, overriding those of its
+    /// section.
+    pub deadline: Option<Deadlines>,
+    /// Policy of the circuit breakers of the model's backends, overriding that of
+    /// its section.
+    pub circuit_breaker: Option<CircuitBreakerPolicy>,
+    /// Policy that requests for the model are balanced across the endpoints of
+    /// its upstreams with, if any.
+    pub load_balancing: Option<LoadBalancing>,
+    /// Splitting of the model's large batches into sub-batches which are sent
+    /// upstream concurrently, if they are split.
+    pub batch_split: Option<BatchSplit>,
+    /// Micro-batching of concurrent single requests for the model, if they are
+    /// batched.
+    pub micro_batching: Option<MicroBatching>,
+    /// Caching of the responses to the model's deterministic requests, if they
+    /// are cached.
+    pub cache: Option<CachePolicy>,
+    /// De-duplication of identical concurrent requests for the model, if they
+    /// share upstream calls.
+    pub single_flight: Option<SingleFlightPolicy>,
+    /// Backends of the model's adapters by `adapter_id`, which only some of its
+    /// replicas serve. They share the settings of `upstream`, and requests for
+    /// other adapters are sent to the model's own backends.
+    pub adapters: BTreeMap<String, Vec<Backend>>,
+    /// Alternative names of the model. Requests for an alias are sent upstream
+    /// with the model's own name.
+    pub aliases: Vec<String>,
+    /// Patterns of model ids routed to this upstream as-is, either globs
+    /// (e.g. `ibm/granite-*`) or regular expressions prefixed with `regex:`.
+    pub patterns: Vec<String>,
+}
+
+impl ModelRoute {
+    fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            backends: vec![],
+            failover: vec![],
+            shadow: None,
+            retry: None,
+            deadline: None,
+            circuit_breaker: None,
+            load_balancing: None,
+            batch_split: None,
+            micro_batching: None,
+            cache: None,
+            single_flight: None,
+            adapters: BTreeMap::new(),
+            aliases: vec![],
+            patterns: vec![],
+        }
+    }
+
+    /// Upstreams of the failover backends of the route, in order.
+    pub fn failover_upstreams(&self) -> Vec<Upstream> {
+        self.failover
+            .iter()
+            .map(|address| self.upstream_at(address))
+            .collect()
+    }
+
+    /// Upstream of the shadow of the route, if it has one.
+    pub fn shadow_upstream(&self) -> Option<Upstream> {
+        self.shadow
+            .as_ref()
+            .map(|address| self.upstream_at(address))
+    }
+
+    /// Every upstream that requests for the model may be sent to.
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        let backends = self
+            .backend_upstreams()
+            .into_iter()
+            .map(|(upstream, _)| upstream);
+        let adapters = self
+            .adapters
+            .values()
+            .flatten()
+            .map(|backend| self.upstream_at(&backend.address));
+        backends
+            .chain(self.failover_upstreams())
+            .chain(self.shadow_upstream())
+            .chain(adapters)
+            .collect()
+    }
+
+    /// Route of the model's `adapter`, if it is routed to its own backends. It
+    /// has the settings of this route but no failover backends or shadow, which
+    /// may not serve the adapter.
+    pub fn adapter_route(&self, adapter: &str) -> Option<ModelRoute> {
+        let backends = self.adapters.get(adapter)?;
+        Some(ModelRoute {
+            upstream: self.upstream_at(&backends[0].address),
+            backends: backends.clone(),
+            failover: vec![],
+            shadow: None,
+            adapters: BTreeMap::new(),
+            aliases: vec![],
+            patterns: vec![],
+            ..self.clone()
+        })
+    }
+
+    /// The upstream of the route with its address replaced by `address`.
+    fn upstream_at(&self, address: &ServiceAddr) -> Upstream {
+        Upstream {
+            address: address.clone(),
+            ..self.upstream.clone()
+        }
+    }
+
+    /// Upstreams of each backend of the route along with their weights, which is
+    /// just the route's own upstream if its traffic isn't split.
+    pub fn backend_upstreams(&self) -> Vec<(Upstream, u32)> {
+        if self.backends.is_empty() {
+            return vec![(self.upstream.clone(), 1)];
+        }
+        self.backends
+            .iter()
+            .map(|backend| (self.upstream_at(&backend.address), backend.weight))
+            .collect()
+    }
+}
+
+/// Deserialized from either an address string or a mapping, as in the V3 format.
+impl<'de> Deserialize<'de> for ModelRoute {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        model_route_from_str_or_map(deserializer)
+    }
+}
+
+/// Serialized as an address string if it has no other settings, otherwise as a
+/// mapping as in the V3 format.
+impl Serialize for ModelRoute {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if *self == ModelRoute::new(Upstream::new(self.upstream.address.clone())) {
+            return self.upstream.address.serialize(serializer);
+        }
+        ModelRouteFields::from(self).serialize(serializer)
+    }
+}
+
+impl fmt::Display for ModelRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.backends.is_empty() {
+            return self.upstream.fmt(f);
+        }
+        for (i, backend) in self.backends.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} (weight {})", backend.address, backend.weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mapping form of a [`ModelRoute`] in the V3 format, with the fields of its
+/// [`Upstream`] inlined.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ModelRouteFields {
+    #[serde(
+        default,
+        deserialize_with = "opt_service_addr_from_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    address: Option<ServiceAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    backends: Vec<Backend>,
+    #[serde(
+        default,
+        deserialize_with = "de_service_addrs",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    failover: Vec<ServiceAddr>,
+    #[serde(
+        default,
+        deserialize_with = "opt_service_addr_from_str",
+        skip_serializing_if = "Option::is_none"
+    )]
+    shadow: Option<ServiceAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deadline: Option<Deadlines>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    circuit_breaker: Option<CircuitBreakerPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    load_balancing: Option<LoadBalancing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    batch_split: Option<BatchSplit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    micro_batching: Option<MicroBatching>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache: Option<CachePolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    single_flight: Option<SingleFlightPolicy>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    adapters: BTreeMap<String, Vec<Backend>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_server_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_message_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    patterns: Vec<String>,
+}
+
+impl From<&ModelRoute> for ModelRouteFields {
+    fn from(route: &ModelRoute) -> Self {
+        let upstream = route.upstream.clone();
+        Self {
+            address: route.backends.is_empty().then_some(upstream.address),
+            backends: route.backends.clone(),
+            failover: route.failover.clone(),
+            shadow: route.shadow.clone(),
+            retry: route.retry.clone(),
+            deadline: route.deadline.clone(),
+            circuit_breaker: route.circuit_breaker.clone(),
+            load_balancing: route.load_balancing.clone(),
+            batch_split: route.batch_split.clone(),
+            micro_batching: route.micro_batching.clone(),
+            cache: route.cache.clone(),
+            single_flight: route.single_flight.clone(),
+            adapters: route.adapters.clone(),
+            tls: upstream.tls,
+            ca_cert_path: upstream.ca_cert_path,
+            tls_server_name: upstream.tls_server_name,
+            timeout_ms: upstream.timeout_ms,
+            max_message_size: upstream.max_message_size,
+            metadata: upstream.metadata,
+            aliases: route.aliases.clone(),
+            patterns: route.patterns.clone(),
+        }
+    }
+}
+
+impl TryFrom<ModelRouteFields> for ModelRoute {
+    type Error = String;
+
+    fn try_from(fields: ModelRouteFields) -> Result<Self, Self::Error> {
+        let address = match (fields.address, fields.backends.first()) {
+            (Some(address), None) => address,
+            (None, Some(first)) => first.address.clone(),
+            (Some(_), Some(_)) => return Err("only one of address or backends can be given".into()),
+            (None, None) => return Err("missing field `address` or `backends`".into()),
+        };
+        if !fields.backends.is_empty() && fields.backends.iter().all(|b| b.weight == 0) {
+            return Err("at least one backend must have a non-zero weight".into());
+        }
+        if let Some(retry) = &fields.retry {
+            retry.check().map_err(|e| format!("retry: {e}"))?;
+        }
+        if let Some(deadline) = &fields.deadline {
+            deadline
+                .check(&SECTIONS)
+                .map_err(|e| format!("deadline: {e}"))?;
+        }
+        if let Some(circuit_breaker) = &fields.circuit_breaker {
+            circuit_breaker
+                .check()
+                .map_err(|e| format!("circuit_breaker: {e}"))?;
+        }
+        if let Some(load_balancing) = &fields.load_balancing {
+            load_balancing
+                .check()
+                .map_err(|e| format!("load_balancing: {e}"))?;
+        }
+        if let Some(batch_split) = &fields.batch_split {
+            batch_split
+                .check()
+                .map_err(|e| format!("batch_split: {e}"))?;
+        }
+        if let Some(micro_batching) = &fields.micro_batching {
+            micro_batching
+                .check()
+                .map_err(|e| format!("micro_batching: {e}"))?;
+        }
+        if let Some(cache) = &fields.cache {
+            cache.check(&SECTIONS).map_err(|e| format!("cache: {e}"))?;
+        }
+        if let Some(single_flight) = &fields.single_flight {
+            single_flight
+                .check(&SECTIONS)
+                .map_err(|e| format!("single_flight: {e}"))?;
+        }
+        for (adapter, backends) in &fields.adapters {
+            if backends.is_empty() {
+                return Err(format!("adapters: {adapter} has no backends"));
+            }
+            if backends.iter().all(|b| b.weight == 0) {
+                return Err(format!(
+                    "adapters: {adapter} must have a backend with a non-zero weight"
+                ));
+            }
+            let mut seen = HashSet::new();
+            if let Some(b) = backends.iter().find(|b| !seen.insert(&b.address)) {
+                return Err(format!(
+                    "adapters: {adapter} has backend {} more than once",
+                    b.address
+                ));
+            }
+        }
+        let mut addresses = match fields.backends.is_empty() {
+            true => vec![&address],
+            false => fields.backends.iter().map(|b| &b.address).collect(),
+        };
+        addresses.extend(&fields.failover);
+        addresses.extend(&fields.shadow);
+        let mut seen = HashSet::new();
+        for address in &addresses {
+            if !seen.insert(*address) {
+                return Err(format!("address {address} is given more than once"));
+            }
+        }
+        // Adapters may be served by the model's own backends
+        addresses.extend(fields.adapters.values().flatten().map(|b| &b.address));
+        for address in addresses {
+            if let (Some(tls), Some(scheme_tls)) = (fields.tls, address.scheme_tls()) {
+                if tls != scheme_tls {
+                    return Err(format!("tls: {tls} conflicts with address {address}"));
+                }
+            }
+            if address.is_unix() && fields.tls_server_name.is_some() {
+                return Err(format!(
+                    "tls_server_name can't be used with address {address}"
+                ));
+            }
+        }
+        Ok(Self {
+            upstream: Upstream {
+                address,
+                tls: fields.tls,
+                ca_cert_path: fields.ca_cert_path,
+                tls_server_name: fields.tls_server_name,
+                timeout_ms: fields.timeout_ms,
+                max_message_size: fields.max_message_size,
+                metadata: fields.metadata,
+                balance_endpoints: fields.load_balancing.is_some(),
+            },
+            backends: fields.backends,
+            failover: fields.failover,
+            shadow: fields.shadow,
+            retry: fields.retry,
+            deadline: fields.deadline,
+            circuit_breaker: fields.circuit_breaker,
+            load_balancing: fields.load_balancing,
+            batch_split: fields.batch_split,
+            micro_batching: fields.micro_batching,
+            cache: fields.cache,
+            single_flight: fields.single_flight,
+            adapters: fields.adapters,
+            aliases: fields.aliases,
+            patterns: fields.patterns,
+        })
+    }
+}
+
+/// Old format without top-level keys, generation models only.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ModelMapV1(#[serde(deserialize_with = "de_service_addr")] HashMap<String, ModelRoute>);
+
+/// New format with top-level keys for generation and embeddings models.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelMapV2 {
+    #[serde(deserialize_with = "de_service_addr", default = "HashMap::default")]
+    generation: HashMap<String, ModelRoute>,
+    #[serde(deserialize_with = "de_service_addr", default = "HashMap::default")]
+    embeddings: HashMap<String, ModelRoute>,
+}
+
+/// Same layout as V2, with per-model upstream settings, aliases and patterns, and
+/// optional fallback routes.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelMapV3 {
+    #[serde(
+        deserialize_with = "de_model_route",
+        serialize_with = "ser_sorted",
+        default = "HashMap::default"
+    )]
+    generation: HashMap<String, ModelRoute>,
+    #[serde(
+        deserialize_with = "de_model_route",
+        serialize_with = "ser_sorted",
+        default = "HashMap::default"
+    )]
+    embeddings: HashMap<String, ModelRoute>,
+    #[serde(default, skip_serializing_if = "FallbackRoutes::is_empty")]
+    fallback: FallbackRoutes,
+    #[serde(default, skip_serializing_if = "RetrySettings::is_empty")]
+    retry: RetrySettings,
+    #[serde(default, skip_serializing_if = "DeadlineSettings::is_empty")]
+    deadlines: DeadlineSettings,
+    #[serde(default, skip_serializing_if = "CircuitBreakerSettings::is_empty")]
+    circuit_breaker: CircuitBreakerSettings,
+}
+
+impl ModelMapV3 {
+    /// Routes of the section named `section`, if it is one of [`SECTIONS`].
+    pub fn routes_mut(&mut self, section: &str) -> Option<&mut HashMap<String, ModelRoute>> {
+        match section {
+            "generation" => Some(&mut self.generation),
+            "embeddings" => Some(&mut self.embeddings),
+            _ => None,
+        }
+    }
+}
+
+/// Routes for model ids which are not configured in a section, which are sent
+/// upstream as-is.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackRoutes {
+    #[serde(
+        deserialize_with = "de_opt_model_route",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    generation: Option<ModelRoute>,
+    #[serde(
+        deserialize_with = "de_opt_model_route",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    embeddings: Option<ModelRoute>,
+}
+
+impl FallbackRoutes {
+    fn is_empty(&self) -> bool {
+        self.generation.is_none() && self.embeddings.is_none()
+    }
+}
+
+/// Maps model names to service address.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ModelMap {
+    V1(ModelMapV1),
+    V2(ModelMapV2),
+    V3(ModelMapV3),
+}
+
+impl ModelMap {
+    /// Loads the model map config at `path`, which is either a single file or a
+    /// directory of fragments, failing with every problem found in it if it is
+    /// invalid.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let validation = validate_path(path)?;
+        match validation.model_map {
+            Some(model_map) if validation.errors.is_empty() => Ok(model_map),
+            _ => Err(ConfigErrors(validation.errors).into()),
+        }
+    }
+
+    /// Parses a model map config, failing with every problem found in it if it is
+    /// invalid. Use [`validate`] to also get warnings.
+    pub fn parse(yaml: &str) -> Result<Self, ConfigErrors> {
+        let validation = validate(yaml);
+        match validation.model_map {
+            Some(model_map) if validation.errors.is_empty() => Ok(model_map),
+            _ => Err(ConfigErrors(validation.errors)),
+        }
+    }
+
+    /// Converts the model map to the V3 format, which can hold any model map.
+    pub fn into_v3(self) -> ModelMapV3 {
+        match self {
+            ModelMap::V1(v1) => ModelMapV3 {
+                generation: v1.0,
+                embeddings: HashMap::new(),
+                fallback: FallbackRoutes::default(),
+                retry: RetrySettings::default(),
+                deadlines: DeadlineSettings::default(),
+                circuit_breaker: CircuitBreakerSettings::default(),
+            },
+            ModelMap::V2(v2) => ModelMapV3 {
+                generation: v2.generation,
+                embeddings: v2.embeddings,
+                fallback: FallbackRoutes::default(),
+                retry: RetrySettings::default(),
+                deadlines: DeadlineSettings::default(),
+                circuit_breaker: CircuitBreakerSettings::default(),
+            },
+            ModelMap::V3(v3) => v3,
+        }
+    }
+
+    /// Routes of the section named `section`, if it is one of [`SECTIONS`] and
+    /// has any routes.
+    pub fn routes(&self, section: &str) -> Option<&HashMap<String, ModelRoute>> {
+        match section {
+            "generation" => self.generation(),
+            "embeddings" => self.embeddings(),
+            _ => None,
+        }
+    }
+
+    pub fn generation(&self) -> Option<&HashMap<String, ModelRoute>> {
+        match self {
+            ModelMap::V1(v1) => Some(&v1.0),
+            ModelMap::V2(v2) => (!v2.generation.is_empty()).then_some(&v2.generation),
+            ModelMap::V3(v3) => (!v3.generation.is_empty()).then_some(&v3.generation),
+        }
+    }
+
+    pub fn embeddings(&self) -> Option<&HashMap<String, ModelRoute>> {
+        match self {
+            ModelMap::V1(_) => None,
+            ModelMap::V2(v2) => (!v2.embeddings.is_empty()).then_some(&v2.embeddings),
+            ModelMap::V3(v3) => (!v3.embeddings.is_empty()).then_some(&v3.embeddings),
+        }
+    }
+
+    /// Fallback route of the section named `section`, if any.
+    pub fn fallback(&self, section: &str) -> Option<&ModelRoute> {
+        match section {
+            "generation" => self.generation_fallback(),
+            "embeddings" => self.embeddings_fallback(),
+            _ => None,
+        }
+    }
+
+    pub fn generation_fallback(&self) -> Option<&ModelRoute> {
+        match self {
+            ModelMap::V3(v3) => v3.fallback.generation.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn embeddings_fallback(&self) -> Option<&ModelRoute> {
+        match self {
+            ModelMap::V3(v3) => v3.fallback.embeddings.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Default retry policy of the models of `section`, if any.
+    pub fn retry_policy(&self, section: &str) -> Option<&RetryPolicy> {
+        let ModelMap::V3(v3) = self else {
+            return None;
+        };
+        match section {
+            "generation" => v3.retry.generation.as_ref(),
+            "embeddings" => v3.retry.embeddings.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Budget that the retries of each service are limited by.
+    pub fn retry_budget(&self) -> RetryBudget {
+        match self {
+            ModelMap::V3(v3) => v3.retry.budget.clone(),
+            _ => RetryBudget::default(),
+        }
+    }
+
+    /// Default deadlines of the models of `section`, if any.
+    pub fn deadlines(&self, section: &str) -> Option<&Deadlines> {
+        let ModelMap::V3(v3) = self else {
+            return None;
+        };
+        match section {
+            "generation" => v3.deadlines.generation.as_ref(),
+            "embeddings" => v3.deadlines.embeddings.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Margin that client deadlines are shortened by when they are propagated
+    /// upstream.
+    pub fn deadline_margin(&self) -> Duration {
+        match self {
+            ModelMap::V3(v3) => Duration::from_millis(v3.deadlines.margin_ms),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Default circuit breaker policy of the backends of the models of `section`,
+    /// if any.
+    pub fn circuit_breaker_policy(&self, section: &str) -> Option<&CircuitBreakerPolicy> {
+        let ModelMap::V3(v3) = self else {
+            return None;
+        };
+        match section {
+            "generation" => v3.circuit_breaker.generation.as_ref(),
+            "embeddings" => v3.circuit_breaker.embeddings.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+fn service_addr_from_str<'de, D>(deserializer: D) -> Result<ServiceAddr, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer).map_err(serde::de::Error::custom)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn opt_service_addr_from_str<'de, D>(deserializer: D) -> Result<Option<ServiceAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    service_addr_from_str(deserializer).map(Some)
+}
+
+fn de_service_addrs<'de, D>(deserializer: D) -> Result<Vec<ServiceAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "service_addr_from_str")] ServiceAddr);
+
+    let v = Vec::<Wrapper>::deserialize(deserializer)?;
+    Ok(v.into_iter().map(|Wrapper(v)| v).collect())
+}
+
+fn de_service_addr<'de, D>(deserializer: D) -> Result<HashMap<String, ModelRoute>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "service_addr_from_str")] ServiceAddr);
+
+    let v = HashMap::<String, Wrapper>::deserialize(deserializer)?;
+    Ok(v.into_iter()
+        .map(|(k, Wrapper(v))| (k, ModelRoute::new(Upstream::new(v))))
+        .collect())
+}
+
+/// Deserializes a [`ModelRoute`] from either an address string or a mapping.
+fn model_route_from_str_or_map<'de, D>(deserializer: D) -> Result<ModelRoute, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ModelRouteVisitor;
+
+    impl<'de> Visitor<'de> for ModelRouteVisitor {
+        type Value = ModelRoute;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a service address string or a model route mapping")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<ModelRoute, E> {
+            let address = service_addr_from_str(serde::de::value::StrDeserializer::<E>::new(s))?;
+            Ok(ModelRoute::new(Upstream::new(address)))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ModelRoute, A::Error> {
+            ModelRouteFields::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                .and_then(|fields| ModelRoute::try_from(fields).map_err(serde::de::Error::custom))
+        }
+    }
+
+    deserializer.deserialize_any(ModelRouteVisitor)
+}
+
+fn de_model_route<'de, D>(deserializer: D) -> Result<HashMap<String, ModelRoute>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "model_route_from_str_or_map")] ModelRoute);
+
+    let v = HashMap::<String, Wrapper>::deserialize(deserializer)?;
+    Ok(v.into_iter().map(|(k, Wrapper(v))| (k, v)).collect())
+}
+
+fn de_opt_model_route<'de, D>(deserializer: D) -> Result<Option<ModelRoute>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "model_route_from_str_or_map")] ModelRoute);
+
+    let v = Option::<Wrapper>::deserialize(deserializer)?;
+    Ok(v.map(|Wrapper(v)| v))
+}
+
+/// Serializes a map with its keys sorted, so that the output is stable.
+fn ser_sorted<S: Serializer>(
+    map: &HashMap<String, ModelRoute>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// Compiles a model id pattern, which is a glob unless prefixed with `regex:`.
+pub(crate) fn pattern_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    if let Some(regex) = pattern.strip_prefix("regex:") {
+        return Regex::new(&format!("^(?:{regex})$"));
+    }
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(hostname: &str, port: Option<u16>, scheme: Option<Scheme>) -> ServiceAddr {
+        ServiceAddr {
+            hostname: hostname.to_string(),
+            port,
+            scheme,
+        }
+    }
+
+    #[test]
+    fn service_addr_from_str() {
+        let cases = [
+            ("localhost", addr("localhost", None, None)),
+            ("localhost:8033", addr("localhost", Some(8033), None)),
+            ("10.0.0.1:8033", addr("10.0.0.1", Some(8033), None)),
+            ("[::1]", addr("::1", None, None)),
+            ("[fd00::1]:8033", addr("fd00::1", Some(8033), None)),
+            (
+                "grpc://host:8033",
+                addr("host", Some(8033), Some(Scheme::Grpc)),
+            ),
+            (
+                "grpcs://host:443/",
+                addr("host", Some(443), Some(Scheme::Grpcs)),
+            ),
+            (
+                "grpcs://[::1]:443",
+                addr("::1", Some(443), Some(Scheme::Grpcs)),
+            ),
+            (
+                "unix:///tmp/tgis.sock",
+                addr("/tmp/tgis.sock", None, Some(Scheme::Unix)),
+            ),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<ServiceAddr>(), Ok(expected), "{s}");
+        }
+    }
+
+    #[test]
+    fn service_addr_from_str_errors() {
+        let cases = [
+            ("", "Missing hostname"),
+            (":8033", "Missing hostname"),
+            ("host:port", "Invalid port"),
+            ("host:70000", "Invalid port"),
+            ("fd00::1", "more than one :"),
+            ("[fd00::1", "Unclosed ["),
+            ("[not-ipv6]:8033", "Invalid IPv6 address"),
+            ("[::1]8033", "Invalid configured service name"),
+            ("http://host:8033", "Unsupported scheme"),
+            ("unix://", "Missing socket path"),
+        ];
+        for (s, expected) in cases {
+            let error = s.parse::<ServiceAddr>().unwrap_err();
+            assert!(error.contains(expected), "{s}: {error}");
+        }
+    }
+
+    #[test]
+    fn service_addr_display_round_trips() {
+        for s in [
+            "localhost",
+            "localhost:8033",
+            "[fd00::1]:8033",
+            "grpc://host:8033",
+            "grpcs://[::1]",
+            "unix:///tmp/tgis.sock",
+        ] {
+            assert_eq!(s.parse::<ServiceAddr>().unwrap().to_string(), s);
+        }
+    }
+}
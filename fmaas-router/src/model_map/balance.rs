@@ -0,0 +1,72 @@
+//! Load balancing policies of models, which balance their requests across the
+//! endpoints that the hostnames of their upstreams resolve to.
+use serde::{Deserialize, Serialize};
+use tonic::codegen::http::HeaderName;
+
+/// Policy that the requests for a model are balanced across the endpoints of its
+/// upstreams with. Without one they are balanced by the channels of the
+/// upstreams, which take no account of the requests.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "policy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LoadBalancing {
+    /// Consistent hashing of generation requests by their session or prompt, so
+    /// that requests which share a prefix hit the prefix cache of the same
+    /// replica.
+    PrefixAffinity(PrefixAffinity),
+    /// Sends each request to whichever of two endpoints picked at random has
+    /// fewer requests in flight, counting open streams, so that long requests
+    /// don't leave some replicas with deep queues while others are idle.
+    LeastRequests {},
+}
+
+impl LoadBalancing {
+    pub(super) fn check(&self) -> Result<(), String> {
+        match self {
+            LoadBalancing::PrefixAffinity(affinity) => affinity.check(),
+            LoadBalancing::LeastRequests {} => Ok(()),
+        }
+    }
+}
+
+/// Settings of [`LoadBalancing::PrefixAffinity`].
+///
+/// Requests are hashed onto a ring of the endpoints by the value of their
+/// `session_header` if they have one, or else by the first `prefix_length`
+/// characters of their prompt. An endpoint is skipped for the next one on the
+/// ring while it has more than `load_factor` times the average number of
+/// requests in flight, so that popular prefixes can't overload a replica.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrefixAffinity {
+    pub prefix_length: usize,
+    pub session_header: String,
+    pub load_factor: f64,
+}
+
+impl Default for PrefixAffinity {
+    fn default() -> Self {
+        Self {
+            prefix_length: 256,
+            session_header: "x-session-id".into(),
+            load_factor: 1.25,
+        }
+    }
+}
+
+impl PrefixAffinity {
+    fn check(&self) -> Result<(), String> {
+        if self.prefix_length == 0 {
+            return Err("prefix_length must be at least 1".into());
+        }
+        if HeaderName::from_lowercase(self.session_header.as_bytes()).is_err() {
+            return Err(format!(
+                "session_header {} is not a valid lowercase header name",
+                self.session_header
+            ));
+        }
+        if !(1.0..).contains(&self.load_factor) {
+            return Err("load_factor must be at least 1".into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! Batching settings of models, which change how the requests in a batch are
+//! sent upstream.
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Splitting of batches with more than `max_batch_size` items into sub-batches of
+/// at most that size, which are sent upstream concurrently so that they can be
+/// served by different replicas, and whose responses are reassembled in the
+/// order of the items.
+///
+/// The items are the requests of generation batches, the texts of embeddings,
+/// the documents of reranking and the sentences of sentence similarity.
+///
+/// The `input_token_count` of a split batch is the sum of those of its
+/// sub-batches. Each sub-batch of reranking or sentence similarity is sent with
+/// all the queries or source sentences, whose tokens upstreams don't count
+/// separately, so they are counted once per sub-batch and the total is higher
+/// than that of the batch sent whole.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSplit {
+    pub max_batch_size: usize,
+    pub on_failure: SplitFailure,
+}
+
+impl Default for BatchSplit {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 32,
+            on_failure: SplitFailure::Fail,
+        }
+    }
+}
+
+impl BatchSplit {
+    pub(super) fn check(&self) -> Result<(), String> {
+        if self.max_batch_size == 0 {
+            return Err("max_batch_size must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// What a split batch returns when some of its sub-batches fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitFailure {
+    /// The whole batch fails with the error of the first sub-batch which failed.
+    Fail,
+    /// The responses to the requests of the sub-batches which failed have stop
+    /// reason `ERROR`, and the batch only fails if all of them did. Only
+    /// generation responses can tell failed requests apart.
+    Partial,
+}
+
+/// Micro-batching of concurrent single requests for a model, which are held for
+/// up to `max_wait_ms` so that up to `max_batch_size` of them can be sent
+/// upstream as one batch.
+///
+/// Single embeddings requests are batched with those which have the same
+/// `truncate_input_tokens`, and generation requests for one prompt with those
+/// which have the same adapter and parameters. Only requests with the same
+/// metadata, such as credentials, are batched together, leaving out their
+/// deadlines and trace context. A batch is sent with the metadata of the request
+/// which started it, by the latest deadline of its requests (if all have one),
+/// while each request still fails by its own deadline.
+/// Upstreams only count the input tokens of a whole embeddings batch, so the
+/// `input_token_count` of texts batched with others is left unset.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MicroBatching {
+    pub max_batch_size: usize,
+    pub max_wait_ms: u64,
+}
+
+impl Default for MicroBatching {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 32,
+            max_wait_ms: 5,
+        }
+    }
+}
+
+impl MicroBatching {
+    pub(super) fn check(&self) -> Result<(), String> {
+        if self.max_batch_size < 2 {
+            return Err("max_batch_size must be at least 2".into());
+        }
+        Ok(())
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+}
@@ -0,0 +1,98 @@
+//! Circuit breakers, which stop sending requests to backends that keep failing so
+//! that they can recover.
+use serde::{Deserialize, Serialize};
+use tonic::Code;
+
+/// Policy of the circuit breakers of a model's backends, which can be configured
+/// for each section in the top-level `circuit_breaker` section and overridden per
+/// model.
+///
+/// A breaker opens when a backend fails `consecutive_failures` requests in a row,
+/// or at least `failure_rate` of its last `window_size` requests. Requests to it
+/// then fail fast with `UNAVAILABLE` (or fail over) for `open_ms`, after which up
+/// to `half_open_requests` probe requests are let through at a time. The breaker
+/// closes once that many of them have succeeded, and opens again if any fails.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerPolicy {
+    pub consecutive_failures: u32,
+    pub failure_rate: f64,
+    pub window_size: u32,
+    pub open_ms: u64,
+    pub half_open_requests: u32,
+    /// Status codes which count as failures, e.g. `UNAVAILABLE`. Other errors are
+    /// the backend's answer to a bad request rather than a sign of trouble.
+    /// `DEADLINE_EXCEEDED` only counts if it is listed and the deadline wasn't set
+    /// by the client.
+    #[serde(
+        deserialize_with = "super::retry::de_status_codes",
+        serialize_with = "super::retry::ser_status_codes"
+    )]
+    pub failure_status_codes: Vec<Code>,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            window_size: 20,
+            open_ms: 10000,
+            half_open_requests: 1,
+            failure_status_codes: vec![
+                Code::Unavailable,
+                Code::ResourceExhausted,
+                Code::Internal,
+                Code::Unknown,
+            ],
+        }
+    }
+}
+
+impl CircuitBreakerPolicy {
+    pub(super) fn check(&self) -> Result<(), String> {
+        if self.consecutive_failures == 0 {
+            return Err("consecutive_failures must be at least 1".into());
+        }
+        if !(self.failure_rate > 0.0 && self.failure_rate <= 1.0) {
+            return Err("failure_rate must be greater than 0 and at most 1".into());
+        }
+        if self.window_size == 0 {
+            return Err("window_size must be at least 1".into());
+        }
+        if self.half_open_requests == 0 {
+            return Err("half_open_requests must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// The top-level `circuit_breaker` section, with the default policy of each
+/// section. Backends don't have circuit breakers unless a policy is configured
+/// for their section or model.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) generation: Option<CircuitBreakerPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) embeddings: Option<CircuitBreakerPolicy>,
+}
+
+impl CircuitBreakerSettings {
+    pub(super) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(super) fn check(&self) -> Result<(), String> {
+        for (section, policy) in [
+            ("generation", &self.generation),
+            ("embeddings", &self.embeddings),
+        ] {
+            if let Some(policy) = policy {
+                policy.check().map_err(|e| format!("{section}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+}
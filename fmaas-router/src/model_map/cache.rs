@@ -0,0 +1,135 @@
+//! Caching and de-duplication of the responses to deterministic requests.
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// RPCs of each section whose responses can be cached, by method name. The
+/// responses to `Generate` requests are only cached for greedy decoding without
+/// a seed.
+const RPCS: [(&str, &[&str]); 2] = [
+    ("generation", &["Generate", "Tokenize", "ModelInfo"]),
+    (
+        "embeddings",
+        &[
+            "EmbeddingTasksPredict",
+            "EmbeddingTaskPredict",
+            "GetModelsInfo",
+        ],
+    ),
+];
+
+/// RPCs of each section whose identical concurrent requests can share one
+/// upstream call, by method name.
+const SINGLE_FLIGHT_RPCS: [(&str, &[&str]); 2] = [
+    ("generation", &["Tokenize"]),
+    (
+        "embeddings",
+        &[
+            "EmbeddingTasksPredict",
+            "EmbeddingTaskPredict",
+            "TokenizationTaskPredict",
+        ],
+    ),
+];
+
+/// Caching of the responses to the requests for a model, which are kept for up
+/// to `ttl_ms` in an LRU cache of at most `max_entries` responses and
+/// `max_bytes` bytes. Requests are looked up by their model id, encoded message
+/// and metadata, and only for the `rpcs` which are listed. Responses are only
+/// returned to requests with the same metadata, such as credentials and tenant,
+/// leaving out their deadlines, trace context and caching directives.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CachePolicy {
+    pub rpcs: Vec<String>,
+    pub ttl_ms: u64,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            rpcs: vec![],
+            ttl_ms: 300000,
+            max_entries: 10000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl CachePolicy {
+    /// Checks the policy, whose RPCs may only be those of `sections`.
+    pub(super) fn check(&self, sections: &[&str]) -> Result<(), String> {
+        check_rpcs(&self.rpcs, &RPCS, sections, "cached")?;
+        if self.ttl_ms == 0 {
+            return Err("ttl_ms must be greater than 0".into());
+        }
+        if self.max_entries == 0 {
+            return Err("max_entries must be at least 1".into());
+        }
+        if self.max_bytes == 0 {
+            return Err("max_bytes must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    /// Whether the responses to requests for `rpc` are cached.
+    pub fn caches(&self, rpc: &str) -> bool {
+        self.rpcs.iter().any(|cached| cached == rpc)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_millis(self.ttl_ms)
+    }
+}
+
+/// De-duplication of identical concurrent requests for a model, which share one
+/// upstream call whose response is returned to all of them, for the `rpcs` which
+/// are listed. Requests are identical if they have the same encoded message,
+/// whatever their metadata, so this must only be enabled for models whose
+/// responses don't depend on who is asking.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SingleFlightPolicy {
+    pub rpcs: Vec<String>,
+}
+
+impl SingleFlightPolicy {
+    /// Checks the policy, whose RPCs may only be those of `sections`.
+    pub(super) fn check(&self, sections: &[&str]) -> Result<(), String> {
+        check_rpcs(&self.rpcs, &SINGLE_FLIGHT_RPCS, sections, "de-duplicated")
+    }
+
+    /// Whether identical concurrent requests for `rpc` share one upstream call.
+    pub fn deduplicates(&self, rpc: &str) -> bool {
+        self.rpcs.iter().any(|deduplicated| deduplicated == rpc)
+    }
+}
+
+/// Checks that `rpcs` lists at least one RPC, and only those of `known` which
+/// belong to `sections`.
+fn check_rpcs(
+    rpcs: &[String],
+    known: &[(&str, &[&str])],
+    sections: &[&str],
+    verb: &str,
+) -> Result<(), String> {
+    if rpcs.is_empty() {
+        return Err("rpcs must list at least one RPC".into());
+    }
+    let known: Vec<_> = known
+        .iter()
+        .filter(|(section, _)| sections.contains(section))
+        .flat_map(|(_, rpcs)| rpcs.iter())
+        .collect();
+    for rpc in rpcs {
+        if !known.contains(&&rpc.as_str()) {
+            return Err(format!(
+                "RPC {rpc} can't be {verb}, expected one of {}",
+                known.iter().map(|rpc| **rpc).collect::<Vec<_>>().join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
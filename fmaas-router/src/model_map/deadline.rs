@@ -0,0 +1,129 @@
+//! Default deadlines of requests which arrive without a `grpc-timeout`.
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// RPCs of each section which deadlines can be configured for, by method name.
+const RPCS: [(&str, &[&str]); 2] = [
+    (
+        "generation",
+        &["Generate", "GenerateStream", "Tokenize", "ModelInfo"],
+    ),
+    (
+        "embeddings",
+        &[
+            "EmbeddingTasksPredict",
+            "EmbeddingTaskPredict",
+            "RerankTaskPredict",
+            "RerankTasksPredict",
+            "SentenceSimilarityTaskPredict",
+            "SentenceSimilarityTasksPredict",
+            "TokenizationTaskPredict",
+            "GetModelsInfo",
+        ],
+    ),
+];
+
+/// Default deadlines of the requests for a model or section, which can be
+/// configured for each section in the top-level `deadlines` section and
+/// overridden per model.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deadlines {
+    /// Deadline of requests for any RPC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_ms: Option<u64>,
+    /// Deadlines of requests for specific RPCs by method name, e.g. `Tokenize`,
+    /// which take precedence over `default_ms`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rpcs: BTreeMap<String, u64>,
+}
+
+impl Deadlines {
+    /// Checks the deadlines, which may only be given for the RPCs of `sections`.
+    pub(super) fn check(&self, sections: &[&str]) -> Result<(), String> {
+        if self.default_ms == Some(0) {
+            return Err("default_ms must be greater than 0".into());
+        }
+        let rpcs: Vec<_> = RPCS
+            .iter()
+            .filter(|(section, _)| sections.contains(section))
+            .flat_map(|(_, rpcs)| rpcs.iter())
+            .collect();
+        for (rpc, timeout_ms) in &self.rpcs {
+            if !rpcs.contains(&&rpc.as_str()) {
+                return Err(format!(
+                    "unknown RPC {rpc}, expected one of {}",
+                    rpcs.iter().map(|rpc| **rpc).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            if *timeout_ms == 0 {
+                return Err(format!("rpcs: {rpc} must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Default deadline of requests for `rpc`, if any.
+    pub fn get(&self, rpc: &str) -> Option<Duration> {
+        self.rpcs
+            .get(rpc)
+            .copied()
+            .or(self.default_ms)
+            .map(Duration::from_millis)
+    }
+
+    /// These deadlines with those of `defaults` (if any) for the RPCs they don't
+    /// cover.
+    pub fn or(&self, defaults: Option<&Deadlines>) -> Deadlines {
+        let Some(defaults) = defaults.filter(|_| self.default_ms.is_none()) else {
+            return self.clone();
+        };
+        let mut rpcs = defaults.rpcs.clone();
+        rpcs.extend(self.rpcs.clone());
+        Deadlines {
+            default_ms: defaults.default_ms,
+            rpcs,
+        }
+    }
+}
+
+/// The top-level `deadlines` section, with the default deadlines of each section
+/// and the margin that client deadlines are reduced by when they are propagated
+/// upstream.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadlineSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) generation: Option<Deadlines>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) embeddings: Option<Deadlines>,
+    /// Time left for the router to return a response to the client, which the
+    /// deadlines of upstream requests are shortened by.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(super) margin_ms: u64,
+}
+
+impl DeadlineSettings {
+    pub(super) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(super) fn check(&self) -> Result<(), String> {
+        for (section, deadlines) in [
+            ("generation", &self.generation),
+            ("embeddings", &self.embeddings),
+        ] {
+            if let Some(deadlines) = deadlines {
+                deadlines
+                    .check(&[section])
+                    .map_err(|e| format!("{section}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_zero(margin_ms: &u64) -> bool {
+    *margin_ms == 0
+}
@@ -0,0 +1,228 @@
+//! Model maps made of a directory of fragments, each of which is a model map of
+//! its own, e.g. one per key of a mounted Kubernetes ConfigMap.
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use super::{
+    validate, validation::find_line, ConfigIssue, ModelMap, ModelMapV3, Validation, SECTIONS,
+};
+
+/// Symlink to the current version of the files of a mounted ConfigMap or Secret,
+/// which Kubernetes swaps atomically when they are updated.
+const K8S_DATA_DIR: &str = "..data";
+
+/// Validates the fragments of a model map directory and merges them into one
+/// model map. Files without a `.yaml` or `.yml` extension and hidden files are
+/// ignored.
+///
+/// Models, aliases, fallback routes and each section of settings (such as
+/// `retry`) may only be configured in one fragment.
+/// If the directory is a Kubernetes volume mount the files are read from the
+/// current version of the volume, so that an update is never seen half-applied.
+pub(crate) fn validate_dir(dir: &Path) -> anyhow::Result<Validation> {
+    let files = fragment_files(&data_dir(dir))
+        .with_context(|| format!("Failed to list model map directory {}", dir.display()))?;
+    let mut merged = Merged::default();
+    let mut warnings = vec![];
+    for path in &files {
+        let file = path.file_name().unwrap().to_string_lossy().into_owned();
+        let yaml = fs::read_to_string(path)
+            .with_context(|| format!("Failed to load model map config {}", path.display()))?;
+        let validation = validate(&yaml);
+        let in_file = |issue: ConfigIssue| ConfigIssue {
+            file: Some(file.clone()),
+            ..issue
+        };
+        merged
+            .errors
+            .extend(validation.errors.into_iter().map(in_file));
+        warnings.extend(validation.warnings.into_iter().map(in_file));
+        if let Some(model_map) = validation.model_map {
+            merged.merge(&file, &yaml, model_map.into_v3());
+        }
+    }
+    if files.is_empty() {
+        merged.errors.push(ConfigIssue {
+            file: None,
+            line: None,
+            location: "config".to_string(),
+            message: format!("no model map files found in {}", dir.display()),
+        });
+    }
+    Ok(Validation {
+        model_map: merged
+            .errors
+            .is_empty()
+            .then_some(ModelMap::V3(merged.model_map)),
+        errors: merged.errors,
+        warnings,
+    })
+}
+
+/// Directory to read the fragments from, which is the current version of the
+/// volume if `dir` is a Kubernetes volume mount.
+fn data_dir(dir: &Path) -> PathBuf {
+    fs::canonicalize(dir.join(K8S_DATA_DIR)).unwrap_or_else(|_| dir.to_path_buf())
+}
+
+/// Model map files in `dir`, sorted by name.
+fn fragment_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        let yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        // Follows symlinks, as the files of volume mounts are
+        if !hidden && yaml && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Default)]
+struct Merged {
+    model_map: ModelMapV3,
+    errors: Vec<ConfigIssue>,
+    /// Files that the model ids (including aliases) of each section were
+    /// configured in.
+    origins: HashMap<(&'static str, String), String>,
+    /// Files that the fallback route of each section was configured in.
+    fallback_origins: HashMap<&'static str, String>,
+    /// Files that the settings of each top-level settings section were configured
+    /// in.
+    settings_origins: HashMap<&'static str, String>,
+}
+
+impl Merged {
+    fn merge(&mut self, file: &str, yaml: &str, mut fragment: ModelMapV3) {
+        for section in SECTIONS {
+            let routes = std::mem::take(fragment.routes_mut(section).unwrap());
+            for (name, route) in routes {
+                let mut conflict = false;
+                for id in std::iter::once(&name).chain(&route.aliases) {
+                    let origin = self
+                        .origins
+                        .entry((section, id.clone()))
+                        .or_insert_with(|| file.to_string())
+                        .clone();
+                    if origin != file {
+                        let message = format!("model id {id} is also configured in {origin}");
+                        self.error(
+                            file,
+                            find_line(yaml, Some(section), &name),
+                            section,
+                            &name,
+                            message,
+                        );
+                        conflict = true;
+                    }
+                }
+                if !conflict {
+                    self.model_map
+                        .routes_mut(section)
+                        .unwrap()
+                        .insert(name, route);
+                }
+            }
+        }
+
+        let fallbacks = [
+            (
+                "generation",
+                fragment.fallback.generation,
+                &mut self.model_map.fallback.generation,
+            ),
+            (
+                "embeddings",
+                fragment.fallback.embeddings,
+                &mut self.model_map.fallback.embeddings,
+            ),
+        ];
+        for (section, route, merged) in fallbacks {
+            let Some(route) = route else {
+                continue;
+            };
+            match self.fallback_origins.get(section) {
+                Some(origin) => {
+                    let message = format!("fallback is also configured in {origin}");
+                    self.errors.push(ConfigIssue {
+                        file: Some(file.to_string()),
+                        line: find_line(yaml, Some("fallback"), section),
+                        location: format!("{section} fallback"),
+                        message,
+                    });
+                }
+                None => {
+                    self.fallback_origins.insert(section, file.to_string());
+                    *merged = Some(route);
+                }
+            }
+        }
+
+        self.merge_settings(file, yaml, "retry", fragment.retry, |m| &mut m.retry);
+        self.merge_settings(file, yaml, "deadlines", fragment.deadlines, |m| {
+            &mut m.deadlines
+        });
+        let circuit_breaker = fragment.circuit_breaker;
+        self.merge_settings(file, yaml, "circuit_breaker", circuit_breaker, |m| {
+            &mut m.circuit_breaker
+        });
+    }
+
+    /// Merges the settings of the top-level section `section` of a fragment, which
+    /// may only be configured in one fragment.
+    fn merge_settings<T: Default + PartialEq>(
+        &mut self,
+        file: &str,
+        yaml: &str,
+        section: &'static str,
+        settings: T,
+        merged: impl FnOnce(&mut ModelMapV3) -> &mut T,
+    ) {
+        if settings == T::default() {
+            return;
+        }
+        match self.settings_origins.get(section) {
+            Some(origin) => {
+                let message = format!("{section} settings are also configured in {origin}");
+                self.errors.push(ConfigIssue {
+                    file: Some(file.to_string()),
+                    line: find_line(yaml, None, section),
+                    location: format!("section [{section}]"),
+                    message,
+                });
+            }
+            None => {
+                self.settings_origins.insert(section, file.to_string());
+                *merged(&mut self.model_map) = settings;
+            }
+        }
+    }
+
+    fn error(
+        &mut self,
+        file: &str,
+        line: Option<usize>,
+        section: &str,
+        name: &str,
+        message: String,
+    ) {
+        self.errors.push(ConfigIssue {
+            file: Some(file.to_string()),
+            line,
+            location: format!("{section} model [{name}]"),
+            message,
+        });
+    }
+}
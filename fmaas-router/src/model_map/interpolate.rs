@@ -0,0 +1,214 @@
+//! Interpolation of environment variables and files into model map values, so
+//! that the same config can be used across environments.
+//!
+//! - `${VAR}` is replaced by the value of the environment variable `VAR`, which
+//!   must be set
+//! - `${VAR:-default}` is replaced by `default` if `VAR` is unset or empty
+//! - `${file:/path}` is replaced by the contents of the file, without trailing
+//!   whitespace, e.g. for secrets mounted as files
+//! - `$${` is replaced by a literal `${`
+use std::borrow::Cow;
+
+use serde_yaml::Value;
+
+/// Fields whose values are parsed as YAML scalars after interpolation, so that
+/// non-string settings can also be given by variables. The values of `rpcs`
+/// deadlines are too.
+const TYPED_FIELDS: &[&str] = &[
+    "tls",
+    "timeout_ms",
+    "max_message_size",
+    "weight",
+    "max_attempts",
+    "initial_backoff_ms",
+    "max_backoff_ms",
+    "backoff_multiplier",
+    "max_tokens",
+    "token_ratio",
+    "default_ms",
+    "margin_ms",
+    "consecutive_failures",
+    "failure_rate",
+    "window_size",
+    "open_ms",
+    "half_open_requests",
+    "prefix_length",
+    "load_factor",
+    "max_batch_size",
+    "max_wait_ms",
+    "ttl_ms",
+    "max_entries",
+    "max_bytes",
+];
+
+/// Interpolates all string values within `value` (but not keys), calling
+/// `on_error` with the path of the keys leading to each value that could not be
+/// interpolated.
+pub(super) fn interpolate_values(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    on_error: &mut impl FnMut(&[String], String),
+) {
+    match value {
+        Value::String(s) => match interpolate(s) {
+            Ok(Cow::Owned(interpolated)) => {
+                let typed = match &path[..] {
+                    [.., parent, _] if parent == "rpcs" => true,
+                    [.., field] => TYPED_FIELDS.contains(&field.as_str()),
+                    [] => false,
+                };
+                *value = match serde_yaml::from_str(&interpolated) {
+                    Ok(scalar @ (Value::Bool(_) | Value::Number(_))) if typed => scalar,
+                    _ => Value::String(interpolated),
+                };
+            }
+            Ok(Cow::Borrowed(_)) => {}
+            Err(e) => on_error(path, e),
+        },
+        Value::Sequence(values) => {
+            for value in values {
+                interpolate_values(value, path, on_error);
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping {
+                path.push(match key {
+                    Value::String(key) => key.clone(),
+                    key => format!("{key:?}"),
+                });
+                interpolate_values(value, path, on_error);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces the references in `s`, returning it as-is if it has none.
+fn interpolate(s: &str) -> Result<Cow<'_, str>, String> {
+    if !s.contains("${") {
+        return Ok(Cow::Borrowed(s));
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated reference in {s:?}"))?;
+        out.push_str(&resolve(&rest[start + 2..start + end])?);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Cow::Owned(out))
+}
+
+fn resolve(reference: &str) -> Result<String, String> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        return std::fs::read_to_string(path)
+            .map(|contents| contents.trim_end().to_string())
+            .map_err(|e| format!("couldn't read file {path}: {e}"));
+    }
+    let (name, default) = match reference.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (reference, None),
+    };
+    if name.is_empty() {
+        return Err("empty variable name in ${}".to_string());
+    }
+    match (std::env::var(name), default) {
+        (Ok(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => Ok(default.to_string()),
+        (Err(_), None) => Err(format!("environment variable {name} is not set")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_variables() {
+        std::env::set_var("FMAAS_ROUTER_TEST_HOST", "tgis.example");
+        std::env::set_var("FMAAS_ROUTER_TEST_EMPTY", "");
+        std::env::remove_var("FMAAS_ROUTER_TEST_UNSET");
+        let cases = [
+            ("no references", "no references"),
+            ("${FMAAS_ROUTER_TEST_HOST}:8033", "tgis.example:8033"),
+            ("${FMAAS_ROUTER_TEST_UNSET:-localhost}", "localhost"),
+            ("${FMAAS_ROUTER_TEST_EMPTY:-localhost}", "localhost"),
+            ("${FMAAS_ROUTER_TEST_HOST:-localhost}", "tgis.example"),
+            ("${FMAAS_ROUTER_TEST_EMPTY}", ""),
+            ("$${FMAAS_ROUTER_TEST_HOST}", "${FMAAS_ROUTER_TEST_HOST}"),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(interpolate(s).unwrap(), expected, "{s}");
+        }
+        assert!(matches!(interpolate("plain"), Ok(Cow::Borrowed("plain"))));
+    }
+
+    #[test]
+    fn interpolates_files() {
+        let path = std::env::temp_dir().join("fmaas_router_interpolate_test");
+        std::fs::write(&path, "secret\n").unwrap();
+        let s = format!("Bearer ${{file:{}}}", path.display());
+        assert_eq!(interpolate(&s).unwrap(), "Bearer secret");
+        std::fs::remove_file(&path).unwrap();
+        assert!(interpolate(&s).unwrap_err().contains("couldn't read file"));
+    }
+
+    #[test]
+    fn interpolate_errors() {
+        std::env::remove_var("FMAAS_ROUTER_TEST_MISSING");
+        let cases = [
+            ("${FMAAS_ROUTER_TEST_MISSING}", "is not set"),
+            ("${FMAAS_ROUTER_TEST_MISSING", "unterminated reference"),
+            ("${}", "empty variable name"),
+        ];
+        for (s, expected) in cases {
+            let error = interpolate(s).unwrap_err();
+            assert!(error.contains(expected), "{s}: {error}");
+        }
+    }
+
+    #[test]
+    fn parses_typed_fields() {
+        std::env::set_var("FMAAS_ROUTER_TEST_TIMEOUT", "2500");
+        let mut value: Value = serde_yaml::from_str(
+            "timeout_ms: ${FMAAS_ROUTER_TEST_TIMEOUT}\n\
+             tls: ${FMAAS_ROUTER_TEST_BOOL:-true}\n\
+             rpcs:\n  Generate: ${FMAAS_ROUTER_TEST_TIMEOUT}\n\
+             metadata:\n  x-timeout: ${FMAAS_ROUTER_TEST_TIMEOUT}\n",
+        )
+        .unwrap();
+        interpolate_values(&mut value, &mut vec![], &mut |path, e| {
+            panic!("{path:?}: {e}")
+        });
+        let expected: Value = serde_yaml::from_str(
+            "timeout_ms: 2500\ntls: true\nrpcs:\n  Generate: 2500\n\
+             metadata:\n  x-timeout: '2500'\n",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn reports_error_paths() {
+        let mut value: Value =
+            serde_yaml::from_str("models:\n  m:\n    - ${FMAAS_ROUTER_TEST_MISSING_PATH}\n")
+                .unwrap();
+        let mut errors = vec![];
+        interpolate_values(&mut value, &mut vec![], &mut |path, e| {
+            errors.push((path.join("."), e))
+        });
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "models.m");
+    }
+}
@@ -0,0 +1,160 @@
+//! Policies for retrying idempotent requests which fail with a transient error.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tonic::Code;
+
+/// Policy for retrying a request, which can be configured for each section in the
+/// top-level `retry` section and overridden per model.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first.
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry. Delays are picked at random
+    /// up to the bound, which grows by `backoff_multiplier` after each retry.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub backoff_multiplier: f64,
+    /// Status codes which requests are retried on, e.g. `UNAVAILABLE`.
+    #[serde(
+        deserialize_with = "de_status_codes",
+        serialize_with = "ser_status_codes"
+    )]
+    pub retryable_status_codes: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 50,
+            max_backoff_ms: 1000,
+            backoff_multiplier: 2.0,
+            retryable_status_codes: vec![Code::Unavailable],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(super) fn check(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".into());
+        }
+        if self.backoff_multiplier < 1.0 {
+            return Err("backoff_multiplier must be at least 1".into());
+        }
+        if self.max_backoff_ms < self.initial_backoff_ms {
+            return Err("max_backoff_ms must be at least initial_backoff_ms".into());
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket limiting the retries of a service, so that retries can't amplify
+/// the load on upstreams which are failing. Each failed attempt takes a token
+/// and each successful one adds `token_ratio` tokens, up to `max_tokens`.
+/// Requests are only retried while more than half of the tokens are left.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryBudget {
+    pub max_tokens: u32,
+    pub token_ratio: f64,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self {
+            max_tokens: 10,
+            token_ratio: 0.1,
+        }
+    }
+}
+
+/// The top-level `retry` section, with the default retry policy of each section
+/// and the budget that retries of each service are limited by. Requests aren't
+/// retried unless a policy is configured for their section or model.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) generation: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) embeddings: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(super) budget: RetryBudget,
+}
+
+impl RetrySettings {
+    pub(super) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(super) fn check(&self) -> Result<(), String> {
+        for (section, policy) in [
+            ("generation", &self.generation),
+            ("embeddings", &self.embeddings),
+        ] {
+            if let Some(policy) = policy {
+                policy.check().map_err(|e| format!("{section}: {e}"))?;
+            }
+        }
+        if self.budget.max_tokens == 0 {
+            return Err("budget: max_tokens must be at least 1".into());
+        }
+        if self.budget.token_ratio <= 0.0 {
+            return Err("budget: token_ratio must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+fn is_default(budget: &RetryBudget) -> bool {
+    *budget == RetryBudget::default()
+}
+
+/// Names of the status codes which can be retried on, as in gRPC service configs.
+const STATUS_CODES: [(&str, Code); 16] = [
+    ("CANCELLED", Code::Cancelled),
+    ("UNKNOWN", Code::Unknown),
+    ("INVALID_ARGUMENT", Code::InvalidArgument),
+    ("DEADLINE_EXCEEDED", Code::DeadlineExceeded),
+    ("NOT_FOUND", Code::NotFound),
+    ("ALREADY_EXISTS", Code::AlreadyExists),
+    ("PERMISSION_DENIED", Code::PermissionDenied),
+    ("RESOURCE_EXHAUSTED", Code::ResourceExhausted),
+    ("FAILED_PRECONDITION", Code::FailedPrecondition),
+    ("ABORTED", Code::Aborted),
+    ("OUT_OF_RANGE", Code::OutOfRange),
+    ("UNIMPLEMENTED", Code::Unimplemented),
+    ("INTERNAL", Code::Internal),
+    ("UNAVAILABLE", Code::Unavailable),
+    ("DATA_LOSS", Code::DataLoss),
+    ("UNAUTHENTICATED", Code::Unauthenticated),
+];
+
+pub(super) fn de_status_codes<'de, D>(deserializer: D) -> Result<Vec<Code>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            STATUS_CODES
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, code)| *code)
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown status code {name}")))
+        })
+        .collect()
+}
+
+pub(super) fn ser_status_codes<S: Serializer>(
+    codes: &[Code],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(codes.iter().map(|code| {
+        STATUS_CODES
+            .iter()
+            .find(|(_, c)| c == code)
+            .map_or("OK", |(name, _)| *name)
+    }))
+}
@@ -0,0 +1,690 @@
+//! Validation of model map configs, reporting every problem found in a config
+//! rather than stopping at the first one.
+use std::{collections::HashMap, error::Error, fmt, path::Path};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+
+use super::{
+    dir::validate_dir, interpolate::interpolate_values, model_route_from_str_or_map, pattern_regex,
+    service_addr_from_str, CircuitBreakerSettings, DeadlineSettings, LoadBalancing, ModelMap,
+    ModelMapV3, ModelRoute, RetrySettings, SplitFailure, SECTIONS,
+};
+
+const FALLBACK: &str = "fallback";
+const RETRY: &str = "retry";
+const DEADLINES: &str = "deadlines";
+const CIRCUIT_BREAKER: &str = "circuit_breaker";
+/// Top-level sections holding settings rather than routes.
+const SETTINGS: [&str; 3] = [RETRY, DEADLINES, CIRCUIT_BREAKER];
+
+/// A problem found in a model map config.
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    /// File of a model map directory the problem was found in.
+    pub file: Option<String>,
+    /// 1-based line of the config the problem was found at, if known.
+    pub line: Option<usize>,
+    /// Section and model the problem was found in, e.g. `generation model [bloom]`.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}: ")?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Every error found in an invalid model map config.
+#[derive(Debug, Clone)]
+pub struct ConfigErrors(pub Vec<ConfigIssue>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid model map config:")?;
+        for issue in &self.0 {
+            write!(f, "\n  {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+/// Result of validating a model map config.
+#[derive(Debug)]
+pub struct Validation {
+    /// The parsed model map, if the config could be parsed.
+    pub model_map: Option<ModelMap>,
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+}
+
+/// Validates the model map config at `path`, which is either a single file or a
+/// directory of fragments (see [`validate_dir`](super::dir::validate_dir)).
+pub fn validate_path(path: impl AsRef<Path>) -> anyhow::Result<Validation> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        return validate_dir(path);
+    }
+    let yaml = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to load model map config {}", path.display()))?;
+    Ok(validate(&yaml))
+}
+
+/// Validates a model map config, collecting all errors and warnings in it.
+pub fn validate(yaml: &str) -> Validation {
+    let mut validator = Validator {
+        yaml,
+        interpolate: true,
+        errors: vec![],
+        warnings: vec![],
+    };
+    let model_map = validator.validate();
+    Validation {
+        model_map: model_map.filter(|_| validator.errors.is_empty()),
+        errors: validator.errors,
+        warnings: validator.warnings,
+    }
+}
+
+/// Validates a model map which was changed at runtime, as the config it would be
+/// written as. Its values were already interpolated when it was loaded, so they
+/// aren't again, and the issues have no lines since there is no config file.
+pub(crate) fn validate_v3(model_map: &ModelMapV3) -> Validation {
+    let yaml = match serde_yaml::to_string(model_map) {
+        Ok(yaml) => yaml,
+        Err(e) => {
+            return Validation {
+                model_map: None,
+                errors: vec![ConfigIssue {
+                    file: None,
+                    line: None,
+                    location: "config".to_string(),
+                    message: e.to_string(),
+                }],
+                warnings: vec![],
+            }
+        }
+    };
+    let mut validator = Validator {
+        yaml: &yaml,
+        interpolate: false,
+        errors: vec![],
+        warnings: vec![],
+    };
+    let model_map = validator.validate();
+    let without_line = |issue: ConfigIssue| ConfigIssue {
+        line: None,
+        ..issue
+    };
+    Validation {
+        model_map: model_map.filter(|_| validator.errors.is_empty()),
+        errors: validator.errors.into_iter().map(without_line).collect(),
+        warnings: validator.warnings.into_iter().map(without_line).collect(),
+    }
+}
+
+struct Validator<'a> {
+    yaml: &'a str,
+    /// Whether to interpolate environment variables and files into the values.
+    interpolate: bool,
+    errors: Vec<ConfigIssue>,
+    warnings: Vec<ConfigIssue>,
+}
+
+impl Validator<'_> {
+    fn validate(&mut self) -> Option<ModelMap> {
+        let mut top = match serde_yaml::from_str::<Value>(self.yaml) {
+            Ok(Value::Mapping(top)) => top,
+            Ok(Value::Null) => {
+                self.error(None, "config", "config is empty");
+                return None;
+            }
+            Ok(_) => {
+                self.error(
+                    None,
+                    "config",
+                    "expected a mapping of sections or model names",
+                );
+                return None;
+            }
+            Err(e) => {
+                let line = e.location().map(|l| l.line());
+                self.error(line, "config", e.to_string());
+                return None;
+            }
+        };
+
+        if self.interpolate {
+            self.interpolate(&mut top);
+            if !self.errors.is_empty() {
+                return None;
+            }
+        }
+
+        let sectioned = top.iter().any(|(k, v)| {
+            k.as_str()
+                .is_some_and(|k| SECTIONS.contains(&k) || k == FALLBACK || SETTINGS.contains(&k))
+                && !v.is_string()
+        });
+        if sectioned {
+            self.check_sectioned(&top);
+            top.retain(|key, _| {
+                key.as_str().map_or(true, |key| {
+                    SECTIONS.contains(&key) || key == FALLBACK || SETTINGS.contains(&key)
+                })
+            });
+        } else {
+            self.check_v1(&top);
+        }
+        if !self.errors.is_empty() {
+            return None;
+        }
+
+        // Everything was checked above, so this should only fail if the checks
+        // above are missing something
+        serde_yaml::from_value(Value::Mapping(top))
+            .map_err(|e| {
+                let line = e.location().map(|l| l.line());
+                self.error(line, "config", e.to_string())
+            })
+            .ok()
+    }
+
+    /// Interpolates environment variables and files into the values of the config.
+    fn interpolate(&mut self, top: &mut Mapping) {
+        let mut errors = vec![];
+        for (key, value) in top.iter_mut() {
+            let mut path = vec![key.as_str().unwrap_or_default().to_string()];
+            interpolate_values(value, &mut path, &mut |path, message| {
+                errors.push((path.to_vec(), message))
+            });
+        }
+        let sectioned = top.keys().any(|k| {
+            k.as_str()
+                .is_some_and(|k| SECTIONS.contains(&k) || k == FALLBACK || SETTINGS.contains(&k))
+        });
+        for (path, message) in errors {
+            // Sections hold models, V1 configs only have models at the top level
+            let (line, location, fields) = match &path[..] {
+                [section, key, fields @ ..] if sectioned && section == FALLBACK => (
+                    self.find_line(Some(FALLBACK), key),
+                    format!("{key} fallback"),
+                    fields,
+                ),
+                [section, fields @ ..] if sectioned && SETTINGS.contains(&section.as_str()) => (
+                    self.find_line(None, section),
+                    format!("section [{section}]"),
+                    fields,
+                ),
+                [section, model, fields @ ..] if sectioned => (
+                    self.find_line(Some(section), model),
+                    format!("{section} model [{model}]"),
+                    fields,
+                ),
+                [model, fields @ ..] => (
+                    self.find_line(None, model),
+                    format!("generation model [{model}]"),
+                    fields,
+                ),
+                [] => (None, "config".to_string(), &[][..]),
+            };
+            match fields {
+                [] => self.error(line, &location, message),
+                fields => self.error(line, &location, format!("{}: {message}", fields.join("."))),
+            }
+        }
+    }
+
+    /// Checks the old format without top-level keys, where every model must be
+    /// given as an address string.
+    fn check_v1(&mut self, top: &Mapping) {
+        let mut routes = HashMap::new();
+        for (key, value) in top {
+            let Some(name) = self.key(None, "config", key) else {
+                continue;
+            };
+            let location = format!("generation model [{name}]");
+            match service_addr_from_str(value.clone()) {
+                Ok(address) => {
+                    routes.insert(name, ModelRoute::new(super::Upstream::new(address)));
+                }
+                Err(e) => self.error(self.find_line(None, name), &location, e.to_string()),
+            }
+        }
+        self.check_routes("generation", false, &routes);
+    }
+
+    /// Checks the formats with `generation`, `embeddings` and `fallback` sections,
+    /// and sections of settings.
+    fn check_sectioned(&mut self, top: &Mapping) {
+        for (key, value) in top {
+            let Some(section) = self.key(None, "config", key) else {
+                continue;
+            };
+            let line = self.find_line(None, section);
+            if section == FALLBACK {
+                self.check_fallback(value);
+                continue;
+            }
+            if SETTINGS.contains(&section) {
+                self.check_settings(section, value);
+                continue;
+            }
+            // Unknown sections have always been ignored, and are only warned about
+            if !SECTIONS.contains(&section) {
+                let message = format!(
+                    "unknown section is ignored, expected one of {}, {FALLBACK}, {}",
+                    SECTIONS.join(", "),
+                    SETTINGS.join(", ")
+                );
+                self.warning(line, &format!("section [{section}]"), message);
+                continue;
+            }
+            let models = match value {
+                Value::Mapping(models) if !models.is_empty() => models,
+                Value::Mapping(_) | Value::Null => {
+                    self.warning(
+                        line,
+                        &format!("section [{section}]"),
+                        "no models configured",
+                    );
+                    continue;
+                }
+                _ => {
+                    let location = format!("section [{section}]");
+                    self.error(line, &location, "expected a mapping of model names");
+                    continue;
+                }
+            };
+            let mut routes = HashMap::new();
+            for (key, value) in models {
+                let Some(name) = self.key(Some(section), section, key) else {
+                    continue;
+                };
+                match model_route_from_str_or_map(value.clone()) {
+                    Ok(route) => {
+                        routes.insert(name, route);
+                    }
+                    Err(e) => self.error(
+                        self.find_line(Some(section), name),
+                        &format!("{section} model [{name}]"),
+                        e.to_string(),
+                    ),
+                }
+            }
+            self.check_routes(section, true, &routes);
+        }
+    }
+
+    fn check_fallback(&mut self, value: &Value) {
+        let fallbacks = match value {
+            Value::Mapping(fallbacks) => fallbacks,
+            Value::Null => return,
+            _ => {
+                let line = self.find_line(None, FALLBACK);
+                self.error(line, "section [fallback]", "expected a mapping of sections");
+                return;
+            }
+        };
+        for (key, value) in fallbacks {
+            let Some(section) = self.key(Some(FALLBACK), FALLBACK, key) else {
+                continue;
+            };
+            let line = self.find_line(Some(FALLBACK), section);
+            let location = format!("{section} fallback");
+            if !SECTIONS.contains(&section) {
+                let message = format!("unknown section, expected one of {}", SECTIONS.join(", "));
+                self.error(line, &location, message);
+                continue;
+            }
+            if value.is_null() {
+                continue;
+            }
+            match model_route_from_str_or_map(value.clone()) {
+                Ok(route) => {
+                    if !route.aliases.is_empty() || !route.patterns.is_empty() {
+                        self.error(
+                            line,
+                            &location,
+                            "fallback routes can't have aliases or patterns",
+                        );
+                    }
+                    self.check_upstream(line, &location, &route);
+                    if let Some(Err(e)) = route.cache.as_ref().map(|c| c.check(&[section])) {
+                        self.error(line, &location, format!("cache: {e}"));
+                    }
+                    let single_flight = route.single_flight.as_ref();
+                    if let Some(Err(e)) = single_flight.map(|s| s.check(&[section])) {
+                        self.error(line, &location, format!("single_flight: {e}"));
+                    }
+                    self.check_generation_only(line, &location, section, &route);
+                }
+                Err(e) => self.error(line, &location, e.to_string()),
+            }
+        }
+    }
+
+    /// Checks a top-level section of settings, one of [`SETTINGS`].
+    fn check_settings(&mut self, section: &str, value: &Value) {
+        fn check<T: DeserializeOwned>(
+            value: &Value,
+            check: impl FnOnce(&T) -> Result<(), String>,
+        ) -> Result<(), String> {
+            let settings = serde_yaml::from_value::<T>(value.clone()).map_err(|e| e.to_string())?;
+            check(&settings)
+        }
+        let result = match section {
+            RETRY => check(value, RetrySettings::check),
+            DEADLINES => check(value, DeadlineSettings::check),
+            CIRCUIT_BREAKER => check(value, CircuitBreakerSettings::check),
+            _ => unreachable!("{section} is not a section of settings"),
+        };
+        if let Err(e) = result {
+            let line = self.find_line(None, section);
+            self.error(line, &format!("section [{section}]"), e);
+        }
+    }
+
+    /// Checks the models of a section for conflicting names, invalid patterns and
+    /// settings, and models sharing the same upstream.
+    fn check_routes(&mut self, section: &str, sectioned: bool, routes: &HashMap<&str, ModelRoute>) {
+        let mut names: Vec<_> = routes.iter().collect();
+        names.sort_by_key(|(name, _)| *name);
+
+        let mut owners = HashMap::new();
+        for (name, _) in &names {
+            owners.insert(**name, **name);
+        }
+        let mut hosts = HashMap::new();
+        for (name, route) in &names {
+            let name = **name;
+            let line = self.find_line(sectioned.then_some(section), name);
+            let location = format!("{section} model [{name}]");
+            for alias in &route.aliases {
+                match owners.get(alias.as_str()) {
+                    Some(owner) if *owner == name => {
+                        self.error(
+                            line,
+                            &location,
+                            format!("alias {alias} is the model's own name"),
+                        );
+                    }
+                    Some(owner) => {
+                        let message = format!("alias {alias} is already used by model [{owner}]");
+                        self.error(line, &location, message);
+                    }
+                    None => {
+                        owners.insert(alias, name);
+                    }
+                }
+            }
+            for pattern in &route.patterns {
+                if let Err(e) = pattern_regex(pattern) {
+                    self.error(line, &location, format!("invalid pattern {pattern}: {e}"));
+                }
+            }
+            self.check_upstream(line, &location, route);
+            // Deadlines of any section's RPCs are accepted when parsing a route
+            if let Some(Err(e)) = route.deadline.as_ref().map(|d| d.check(&[section])) {
+                self.error(line, &location, format!("deadline: {e}"));
+            }
+            if let Some(Err(e)) = route.cache.as_ref().map(|c| c.check(&[section])) {
+                self.error(line, &location, format!("cache: {e}"));
+            }
+            if let Some(Err(e)) = route.single_flight.as_ref().map(|s| s.check(&[section])) {
+                self.error(line, &location, format!("single_flight: {e}"));
+            }
+            self.check_generation_only(line, &location, section, route);
+
+            for (upstream, _) in route.backend_upstreams() {
+                let first = *hosts.entry(upstream.address.clone()).or_insert(name);
+                if first != name {
+                    let message = format!(
+                        "same upstream {} as model [{first}], consider using aliases instead",
+                        upstream.address
+                    );
+                    self.warning(line, &location, message);
+                }
+            }
+        }
+    }
+
+    fn check_upstream(&mut self, line: Option<usize>, location: &str, route: &ModelRoute) {
+        if let Err(e) = route.upstream.metadata_headers() {
+            self.error(line, location, format!("{e:#}"));
+        }
+        if let Some(path) = &route.upstream.ca_cert_path {
+            if !Path::new(path).exists() {
+                self.warning(
+                    line,
+                    location,
+                    format!("ca_cert_path {path} does not exist"),
+                );
+            }
+        }
+    }
+
+    /// Checks settings which only apply to generation requests, which carry an
+    /// `adapter_id` and prompts to balance by, and whose responses can tell failed
+    /// requests apart.
+    fn check_generation_only(
+        &mut self,
+        line: Option<usize>,
+        location: &str,
+        section: &str,
+        route: &ModelRoute,
+    ) {
+        if section != "generation" && !route.adapters.is_empty() {
+            self.error(
+                line,
+                location,
+                "adapters are only supported for generation models",
+            );
+        }
+        if section != "generation"
+            && matches!(route.load_balancing, Some(LoadBalancing::PrefixAffinity(_)))
+        {
+            self.error(
+                line,
+                location,
+                "prefix_affinity load balancing is only supported for generation models",
+            );
+        }
+        if section != "generation"
+            && route
+                .batch_split
+                .as_ref()
+                .is_some_and(|split| split.on_failure == SplitFailure::Partial)
+        {
+            self.error(
+                line,
+                location,
+                "batch_split on_failure partial is only supported for generation models",
+            );
+        }
+    }
+
+    fn key<'v>(
+        &mut self,
+        section: Option<&str>,
+        location: &str,
+        key: &'v Value,
+    ) -> Option<&'v str> {
+        let name = key.as_str();
+        if name.is_none() {
+            let line = section.and_then(|section| self.find_line(None, section));
+            self.error(line, location, format!("expected string keys, got {key:?}"));
+        }
+        name
+    }
+
+    fn find_line(&self, section: Option<&str>, key: &str) -> Option<usize> {
+        find_line(self.yaml, section, key)
+    }
+
+    fn error(&mut self, line: Option<usize>, location: &str, message: impl Into<String>) {
+        self.errors.push(ConfigIssue {
+            file: None,
+            line,
+            location: location.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, line: Option<usize>, location: &str, message: impl Into<String>) {
+        self.warnings.push(ConfigIssue {
+            file: None,
+            line,
+            location: location.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Finds the line of `key` in the config, either at the top level or within
+/// the top-level `section`.
+pub(super) fn find_line(yaml: &str, section: Option<&str>, key: &str) -> Option<usize> {
+    let mut lines = yaml.lines().enumerate();
+    if let Some(section) = section {
+        lines.find(|(_, line)| is_key(line, section))?;
+        return lines
+            .take_while(|(_, line)| !is_top_level(line))
+            .find(|(_, line)| is_key(line.trim_start(), key))
+            .map(|(i, _)| i + 1);
+    }
+    lines
+        .find(|(_, line)| is_key(line, key))
+        .map(|(i, _)| i + 1)
+}
+
+fn is_key(line: &str, key: &str) -> bool {
+    [
+        format!("{key}:"),
+        format!("\"{key}\":"),
+        format!("'{key}':"),
+    ]
+    .iter()
+    .any(|k| line.starts_with(k.as_str()))
+}
+
+fn is_top_level(line: &str) -> bool {
+    !line.is_empty() && !line.starts_with([' ', '\t', '#'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(issues: &[ConfigIssue]) -> Vec<String> {
+        issues.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn example_is_valid() {
+        let validation = validate(include_str!("../../../model_map.example.yaml"));
+        assert!(
+            validation.errors.is_empty(),
+            "{:?}",
+            messages(&validation.errors)
+        );
+        assert!(validation.model_map.is_some());
+    }
+
+    #[test]
+    fn collects_all_errors() {
+        let validation = validate(
+            "generation:\n\
+            \x20 a: \"host:port\"\n\
+            \x20 b:\n\
+            \x20   address: b-server\n\
+            \x20   aliases: [c]\n\
+            \x20 c: c-server\n\
+            embeddings:\n\
+            \x20 d:\n\
+            \x20   address: d-server\n\
+            \x20   adapters:\n\
+            \x20     e: [e-server]\n",
+        );
+        assert!(validation.model_map.is_none());
+        assert_eq!(
+            messages(&validation.errors),
+            [
+                "line 2: generation model [a]: Invalid port in configured service name: port",
+                "line 3: generation model [b]: alias c is already used by model [c]",
+                "line 8: embeddings model [d]: adapters are only supported for generation models",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_invalid_settings() {
+        let validation = validate(
+            "generation:\n\
+            \x20 a:\n\
+            \x20   address: a-server\n\
+            \x20   patterns: [\"regex:(\"]\n\
+            \x20   cache:\n\
+            \x20     rpcs: [EmbeddingTasksPredict]\n\
+            fallback:\n\
+            \x20 rerank: r-server\n",
+        );
+        let errors = messages(&validation.errors);
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].contains("generation model [a]: invalid pattern regex:("));
+        assert!(errors[1].starts_with("line 2: generation model [a]: cache: "));
+        assert!(errors[2].starts_with("line 8: rerank fallback: unknown section"));
+    }
+
+    #[test]
+    fn warns_about_unknown_sections() {
+        let validation = validate(
+            "generation:\n\
+            \x20 a: a-server\n\
+            \x20 b: a-server\n\
+            classification:\n\
+            \x20 c: c-server\n",
+        );
+        assert!(
+            validation.errors.is_empty(),
+            "{:?}",
+            messages(&validation.errors)
+        );
+        let warnings = messages(&validation.warnings);
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].starts_with("line 3: generation model [b]: same upstream a-server"));
+        assert!(warnings[1].starts_with("line 4: section [classification]: unknown section"));
+        assert!(validation.model_map.is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        for (yaml, expected) in [
+            ("", "config: config is empty"),
+            (
+                "- a\n- b\n",
+                "config: expected a mapping of sections or model names",
+            ),
+            (
+                "generation: [a]\n",
+                "section [generation]: expected a mapping of model names",
+            ),
+        ] {
+            let validation = validate(yaml);
+            let errors = messages(&validation.errors);
+            assert!(
+                errors.iter().any(|e| e.ends_with(expected)),
+                "{yaml:?}: {errors:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Reloading of the model map while the router is running.
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::time::{interval, Interval, MissedTickBehavior};
+use tracing::{error, info};
+
+use crate::{clients::Upstreams, ModelMap, ModelRoute};
+
+/// Reloads the model map from `path` whenever it changes on disk (checked every
+/// `poll_interval`, if set) or the process receives SIGHUP, and applies it to
+/// `upstreams`. Invalid configs are logged and ignored.
+pub(crate) async fn watch_model_map(
+    path: PathBuf,
+    poll_interval: Option<Duration>,
+    mut current: ModelMap,
+    upstreams: Arc<Upstreams>,
+) {
+    let mut ticker = poll_interval.map(|period| {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        ticker
+    });
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tick(&mut ticker) => {},
+            _ = hangup.recv() => info!("SIGHUP received, reloading model map"),
+        }
+        #[cfg(not(unix))]
+        tick(&mut ticker).await;
+
+        let model_map = match ModelMap::load(&path) {
+            Ok(model_map) => model_map,
+            Err(e) => {
+                error!("Not reloading model map from {}: {e:#}", path.display());
+                continue;
+            }
+        };
+        if model_map == current {
+            continue;
+        }
+        info!(
+            "Model map {} changed, updating upstream clients",
+            path.display()
+        );
+        log_changes("generation", current.generation(), model_map.generation());
+        log_changes("embeddings", current.embeddings(), model_map.embeddings());
+        log_fallback_changes(
+            "generation",
+            current.generation_fallback(),
+            model_map.generation_fallback(),
+        );
+        log_fallback_changes(
+            "embeddings",
+            current.embeddings_fallback(),
+            model_map.embeddings_fallback(),
+        );
+        match upstreams.apply(&model_map).await {
+            Ok(()) => current = model_map,
+            Err(e) => error!("Failed to apply reloaded model map, keeping previous config: {e:#}"),
+        }
+    }
+}
+
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn log_changes(
+    section: &str,
+    old: Option<&HashMap<String, ModelRoute>>,
+    new: Option<&HashMap<String, ModelRoute>>,
+) {
+    let empty = HashMap::new();
+    let (old, new) = (old.unwrap_or(&empty), new.unwrap_or(&empty));
+    for (name, route) in new {
+        match old.get(name) {
+            None => info!("Adding {section} model [{name}] -> {route}"),
+            Some(old_route) if old_route != route => {
+                info!("Updating {section} model [{name}]: {old_route} -> {route}")
+            }
+            _ => {}
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        info!("Removing {section} model [{name}]");
+    }
+}
+
+fn log_fallback_changes(section: &str, old: Option<&ModelRoute>, new: Option<&ModelRoute>) {
+    match (old, new) {
+        (None, Some(route)) => info!("Adding {section} fallback -> {route}"),
+        (Some(old_route), Some(route)) if old_route != route => {
+            info!("Updating {section} fallback: {old_route} -> {route}")
+        }
+        (Some(_), None) => info!("Removing {section} fallback"),
+        _ => {}
+    }
+}
@@ -1,11 +1,54 @@
 pub mod generation;
 pub mod nlp;
 pub mod info;
+mod batcher;
+pub(crate) mod breaker;
+pub(crate) mod cache;
+mod deadline;
+mod failover;
+pub(crate) mod retry;
+mod shadow;
+mod singleflight;
+mod split;
 
-use tonic::{Code, Request, Status};
+use tonic::{metadata::MetadataMap, Code, Request, Status};
 
 
-const METADATA_NAME_MODEL_ID: &str = "mm-model-id";
+pub(crate) const METADATA_NAME_MODEL_ID: &str = "mm-model-id";
+
+/// Metadata which differs between requests that are otherwise the same, or which
+/// is set by gRPC itself rather than forwarded upstream as is: the deadline, the
+/// trace context and caching directives of a request, and transport headers.
+const UNCOMPARED_METADATA: [&str; 9] = [
+    "grpc-timeout",
+    "traceparent",
+    "tracestate",
+    "cache-control",
+    "te",
+    "user-agent",
+    "content-type",
+    "grpc-encoding",
+    "grpc-accept-encoding",
+];
+
+/// Metadata of a request which is forwarded upstream with it, such as its
+/// credentials and tenant, sorted so that requests can be compared by it.
+pub(crate) type CallerMetadata = Vec<(String, Vec<u8>)>;
+
+/// Extracts the [`CallerMetadata`] of a request from its `metadata`. Requests
+/// only share an upstream call or its response if theirs is the same, so that
+/// none is served under another caller's credentials.
+fn caller_metadata(metadata: &MetadataMap) -> CallerMetadata {
+    let mut caller_metadata: CallerMetadata = metadata
+        .clone()
+        .into_headers()
+        .iter()
+        .filter(|(name, _)| !UNCOMPARED_METADATA.contains(&name.as_str()))
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+    caller_metadata.sort_unstable();
+    caller_metadata
+}
 
 /// Extracts model_id from [`Request`] metadata.
 fn extract_model_id<T>(request: &Request<T>) -> Result<&str, Status> {
@@ -22,4 +65,36 @@ fn extract_model_id<T>(request: &Request<T>) -> Result<&str, Status> {
         .to_str()
         .unwrap();
     Ok(model_id)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(headers: &[(&'static str, &'static str)]) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        for (name, value) in headers {
+            metadata.append(*name, value.parse().unwrap());
+        }
+        metadata
+    }
+
+    #[test]
+    fn caller_metadata_compares_forwarded_metadata() {
+        let a = metadata(&[
+            ("authorization", "Bearer a"),
+            ("x-tenant-id", "t"),
+            ("grpc-timeout", "100m"),
+            ("traceparent", "00-aa-01-01"),
+        ]);
+        let b = metadata(&[
+            ("x-tenant-id", "t"),
+            ("authorization", "Bearer a"),
+            ("grpc-timeout", "5S"),
+            ("cache-control", "no-cache"),
+        ]);
+        let c = metadata(&[("authorization", "Bearer c"), ("x-tenant-id", "t")]);
+        assert_eq!(caller_metadata(&a), caller_metadata(&b));
+        assert_ne!(caller_metadata(&a), caller_metadata(&c));
+        assert_eq!(caller_metadata(&a).len(), 2);
+    }
+}
@@ -0,0 +1,340 @@
+//! Micro-batching of concurrent single requests, which are held briefly so that
+//! they can be sent upstream together as one batch.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::future::BoxFuture;
+use tokio::{sync::oneshot, time::Instant};
+use tonic::Status;
+use tracing::{debug, Instrument, Span};
+
+use crate::MicroBatching;
+
+/// Sends the items of a batch upstream by the deadline of the batch (if any),
+/// returning their results in order.
+type SendBatch<T, R> =
+    Box<dyn FnOnce(Vec<T>, Option<Instant>) -> BoxFuture<'static, Result<Vec<R>, Status>> + Send>;
+
+/// Batches of items of type `T` which are waiting to be sent upstream, by key.
+/// Only items with the same key, such as the model and settings of their
+/// requests, are sent together.
+#[derive(Debug)]
+pub(crate) struct Batcher<K, T, R> {
+    rpc: &'static str,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<K, Batch<T, R>>>,
+}
+
+struct Batch<T, R> {
+    /// Identifies the batch, so that its timer doesn't send a later batch with
+    /// the same key.
+    id: u64,
+    model: String,
+    items: Vec<T>,
+    waiters: Vec<oneshot::Sender<Result<R, Status>>>,
+    /// The latest deadline of the requests in the batch, or none if any of them
+    /// has none. Each request still fails by its own deadline, without cutting
+    /// the batch short for the others.
+    deadline: Option<Instant>,
+    /// Span of the request which started the batch, which it is sent in.
+    span: Span,
+    send: SendBatch<T, R>,
+}
+
+impl<T, R> std::fmt::Debug for Batch<T, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Batch")
+            .field("id", &self.id)
+            .field("model", &self.model)
+            .field("len", &self.items.len())
+            .finish()
+    }
+}
+
+impl<K, T, R> Batcher<K, T, R>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    pub fn new(rpc: &'static str) -> Self {
+        Self {
+            rpc,
+            next_id: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `item` of a request for `model` with `deadline` (if any) to the
+    /// pending batch with `key`, starting one if there is none, and waits for its
+    /// result. A batch is sent once it has the maximum size allowed by `settings`
+    /// or has waited for as long as they allow, with the `send` and in the span of
+    /// the request which started it, and by the latest deadline of its requests.
+    pub async fn submit<F, Fut>(
+        self: &Arc<Self>,
+        key: K,
+        model: &str,
+        settings: &MicroBatching,
+        item: T,
+        deadline: Option<Instant>,
+        send: F,
+    ) -> Result<R, Status>
+    where
+        F: FnOnce(Vec<T>, Option<Instant>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Vec<R>, Status>> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.entry(key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let batch = entry.get_mut();
+                    batch.items.push(item);
+                    batch.waiters.push(tx);
+                    batch.deadline = match (batch.deadline, deadline) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
+                    (batch.items.len() >= settings.max_batch_size).then(|| entry.remove())
+                }
+                Entry::Vacant(entry) => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    entry.insert(Batch {
+                        id,
+                        model: model.to_string(),
+                        items: vec![item],
+                        waiters: vec![tx],
+                        deadline,
+                        span: Span::current(),
+                        send: Box::new(move |items, deadline| Box::pin(send(items, deadline))),
+                    });
+                    let batcher = self.clone();
+                    let max_wait = settings.max_wait();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(max_wait).await;
+                        let batch = {
+                            let mut pending = batcher.pending.lock().unwrap();
+                            match pending.get(&key) {
+                                Some(batch) if batch.id == id => pending.remove(&key),
+                                _ => None,
+                            }
+                        };
+                        if let Some(batch) = batch {
+                            batcher.flush(batch).await;
+                        }
+                    });
+                    None
+                }
+            }
+        };
+        // Sent in the background so that it isn't cancelled with this request
+        if let Some(batch) = full {
+            tokio::spawn(self.clone().flush(batch));
+        }
+        rx.await
+            .unwrap_or_else(|_| Err(Status::internal("Batched request was dropped")))
+    }
+
+    /// Sends `batch` upstream and hands each of its waiters its result.
+    async fn flush(self: Arc<Self>, batch: Batch<T, R>) {
+        let len = batch.items.len();
+        debug!(
+            "Sending batch of {len} {} requests for model_id {}",
+            self.rpc, batch.model
+        );
+        metrics::histogram!(
+            "fmaas_router_micro_batch_size",
+            "rpc" => self.rpc,
+            "model" => batch.model.clone()
+        )
+        .record(len as f64);
+        let results = (batch.send)(batch.items, batch.deadline)
+            .instrument(batch.span)
+            .await
+            .and_then(|results| {
+                if results.len() != len {
+                    return Err(Status::internal(format!(
+                        "Upstream returned {} results for a batch of {len}",
+                        results.len()
+                    )));
+                }
+                Ok(results)
+            });
+        // Waiters whose requests were cancelled are gone, so failed sends are
+        // ignored
+        match results {
+            Ok(results) => {
+                for (waiter, result) in batch.waiters.into_iter().zip(results) {
+                    let _ = waiter.send(Ok(result));
+                }
+            }
+            Err(status) => {
+                for waiter in batch.waiters {
+                    let _ = waiter.send(Err(status.clone()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use tonic::Code;
+
+    use super::*;
+
+    type Sent = Arc<Mutex<Vec<(Vec<u32>, Option<Instant>)>>>;
+
+    fn settings(max_batch_size: usize, max_wait_ms: u64) -> MicroBatching {
+        MicroBatching {
+            max_batch_size,
+            max_wait_ms,
+        }
+    }
+
+    /// Submits `items` with `key` concurrently, sending batches which double
+    /// each item, or return `results` instead if given.
+    async fn submit_all(
+        batcher: &Arc<Batcher<&'static str, u32, u32>>,
+        settings: &MicroBatching,
+        items: Vec<(&'static str, u32, Option<Instant>)>,
+        results: Option<Result<Vec<u32>, Status>>,
+        sent: &Sent,
+    ) -> Vec<Result<u32, Status>> {
+        join_all(items.into_iter().map(|(key, item, deadline)| {
+            let sent = sent.clone();
+            let results = results.clone();
+            batcher.submit(
+                key,
+                "model",
+                settings,
+                item,
+                deadline,
+                move |items, deadline| {
+                    sent.lock().unwrap().push((items.clone(), deadline));
+                    let results =
+                        results.unwrap_or_else(|| Ok(items.iter().map(|i| i * 2).collect()));
+                    async move { results }
+                },
+            )
+        }))
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_full_batches_at_once() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let sent = Sent::default();
+        let start = Instant::now();
+        let items = (1..=4).map(|i| ("key", i, None)).collect();
+        let results = submit_all(&batcher, &settings(2, 60_000), items, None, &sent).await;
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, [2, 4, 6, 8]);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        let batches: Vec<_> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(b, _)| b.clone())
+            .collect();
+        assert_eq!(batches, [vec![1, 2], vec![3, 4]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_partial_batches_after_max_wait() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let sent = Sent::default();
+        let start = Instant::now();
+        let items = vec![("a", 1, None), ("b", 2, None), ("a", 3, None)];
+        let results = submit_all(&batcher, &settings(8, 10), items, None, &sent).await;
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, [2, 4, 6]);
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        let mut batches: Vec<_> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(b, _)| b.clone())
+            .collect();
+        batches.sort();
+        assert_eq!(batches, [vec![1, 3], vec![2]]);
+        assert!(batcher.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_batches_by_latest_deadline() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let sent = Sent::default();
+        let now = Instant::now();
+        let (early, late) = (now + Duration::from_secs(1), now + Duration::from_secs(2));
+        let items = vec![("key", 1, Some(late)), ("key", 2, Some(early))];
+        submit_all(&batcher, &settings(2, 10), items, None, &sent).await;
+        let items = vec![("key", 3, Some(early)), ("key", 4, None)];
+        submit_all(&batcher, &settings(2, 10), items, None, &sent).await;
+        let deadlines: Vec<_> = sent.lock().unwrap().iter().map(|(_, d)| *d).collect();
+        assert_eq!(deadlines, [Some(late), None]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_items_leave_the_batch_to_the_others() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let settings = settings(8, 10);
+        let send = |items: Vec<u32>, _| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(items)
+        };
+        let short = tokio::time::timeout(
+            Duration::from_millis(100),
+            batcher.submit("key", "model", &settings, 1, None, send),
+        );
+        let long = batcher.submit("key", "model", &settings, 2, None, send);
+        let (short, long) = tokio::join!(short, long);
+        assert!(short.is_err());
+        assert_eq!(long.unwrap(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_all_items_on_length_mismatch() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let sent = Sent::default();
+        let items = (1..=3).map(|i| ("key", i, None)).collect();
+        let results = submit_all(
+            &batcher,
+            &settings(3, 10),
+            items,
+            Some(Ok(vec![2, 4])),
+            &sent,
+        )
+        .await;
+        for result in results {
+            let status = result.unwrap_err();
+            assert_eq!(status.code(), Code::Internal);
+            assert_eq!(
+                status.message(),
+                "Upstream returned 2 results for a batch of 3"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_all_items_with_send_error() {
+        let batcher = Arc::new(Batcher::new("Test"));
+        let sent = Sent::default();
+        let items = (1..=2).map(|i| ("key", i, None)).collect();
+        let error = Err(Status::unavailable("down"));
+        let results = submit_all(&batcher, &settings(8, 10), items, Some(error), &sent).await;
+        assert!(results
+            .iter()
+            .all(|result| result.as_ref().unwrap_err().code() == Code::Unavailable));
+    }
+}
@@ -0,0 +1,416 @@
+//! Circuit breakers of the backends of models, which fail requests fast while a
+//! backend keeps failing.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::CircuitBreakerPolicy;
+
+/// Circuit breakers of the backends of a model by address.
+pub(crate) type Breakers = HashMap<Arc<str>, Arc<CircuitBreaker>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn as_str(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+
+    /// Value of the state in the `fmaas_router_circuit_breaker_state` gauge.
+    fn gauge(self) -> f64 {
+        match self {
+            BreakerState::Closed => 0.0,
+            BreakerState::HalfOpen => 1.0,
+            BreakerState::Open => 2.0,
+        }
+    }
+}
+
+/// State of a circuit breaker as shown on the probe port.
+#[derive(Debug, Serialize)]
+pub(crate) struct CircuitBreakerStatus {
+    service: &'static str,
+    model: String,
+    backend: String,
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// Share of failures among the latest requests while closed.
+    failure_rate: f64,
+}
+
+/// Circuit breaker of one backend of a model, in one service.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    service: &'static str,
+    model: Arc<str>,
+    backend: Arc<str>,
+    policy: CircuitBreakerPolicy,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    state: BreakerState,
+    opened_at: Instant,
+    consecutive_failures: u32,
+    /// Outcomes of the latest requests while closed, `true` for failures.
+    window: VecDeque<bool>,
+    failures: u32,
+    /// Probe requests in flight while half-open.
+    probes: u32,
+    /// Probe requests which succeeded since the breaker became half-open.
+    successes: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        service: &'static str,
+        model: Arc<str>,
+        backend: Arc<str>,
+        policy: CircuitBreakerPolicy,
+    ) -> Self {
+        let breaker = Self {
+            service,
+            model,
+            backend,
+            policy,
+            state: Mutex::new(State {
+                state: BreakerState::Closed,
+                opened_at: Instant::now(),
+                consecutive_failures: 0,
+                window: VecDeque::new(),
+                failures: 0,
+                probes: 0,
+                successes: 0,
+            }),
+        };
+        breaker.gauge(BreakerState::Closed);
+        breaker
+    }
+
+    /// Whether this is the breaker of `backend` of `model` with `policy`, whose
+    /// state can be kept when the clients of the model are rebuilt.
+    pub fn is_for(&self, model: &str, backend: &str, policy: &CircuitBreakerPolicy) -> bool {
+        &*self.model == model && &*self.backend == backend && self.policy == *policy
+    }
+
+    /// Whether requests to the backend would currently be rejected.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.state {
+            BreakerState::Closed => false,
+            BreakerState::Open => state.opened_at.elapsed() < self.open_duration(),
+            BreakerState::HalfOpen => state.probes >= self.policy.half_open_requests,
+        }
+    }
+
+    /// Lets a request through to the backend unless the breaker is open, or it is
+    /// half-open and already has as many probe requests in flight as it allows.
+    pub fn permit(self: &Arc<Self>) -> Result<Permit, Status> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match state.state {
+            BreakerState::Closed => false,
+            BreakerState::Open if state.opened_at.elapsed() >= self.open_duration() => {
+                self.transition(&mut state, BreakerState::HalfOpen);
+                true
+            }
+            BreakerState::HalfOpen if state.probes < self.policy.half_open_requests => true,
+            BreakerState::Open | BreakerState::HalfOpen => {
+                metrics::counter!(
+                    "fmaas_router_circuit_breaker_rejected_count",
+                    "service" => self.service,
+                    "model" => self.model.to_string(),
+                    "backend" => self.backend.to_string()
+                )
+                .increment(1);
+                return Err(Status::unavailable(format!(
+                    "Circuit breaker of backend {} of model {} is open",
+                    self.backend, self.model
+                )));
+            }
+        };
+        if probe {
+            state.probes += 1;
+        }
+        Ok(Permit {
+            breaker: self.clone(),
+            probe,
+            done: false,
+        })
+    }
+
+    pub fn status(&self) -> CircuitBreakerStatus {
+        let state = self.state.lock().unwrap();
+        CircuitBreakerStatus {
+            service: self.service,
+            model: self.model.to_string(),
+            backend: self.backend.to_string(),
+            state: state.state,
+            consecutive_failures: state.consecutive_failures,
+            failure_rate: match state.window.len() {
+                0 => 0.0,
+                n => state.failures as f64 / n as f64,
+            },
+        }
+    }
+
+    fn record(&self, failed: bool, probe: bool) {
+        let mut state = self.state.lock().unwrap();
+        match state.state {
+            BreakerState::HalfOpen if probe => {
+                state.probes = state.probes.saturating_sub(1);
+                if failed {
+                    self.transition(&mut state, BreakerState::Open);
+                    return;
+                }
+                state.successes += 1;
+                if state.successes >= self.policy.half_open_requests {
+                    self.transition(&mut state, BreakerState::Closed);
+                }
+            }
+            BreakerState::Closed => {
+                state.consecutive_failures = match failed {
+                    true => state.consecutive_failures + 1,
+                    false => 0,
+                };
+                state.window.push_back(failed);
+                state.failures += failed as u32;
+                if state.window.len() > self.policy.window_size as usize {
+                    let oldest = state.window.pop_front().unwrap();
+                    state.failures -= oldest as u32;
+                }
+                let window_full = state.window.len() >= self.policy.window_size as usize;
+                let rate = state.failures as f64 / state.window.len() as f64;
+                if state.consecutive_failures >= self.policy.consecutive_failures
+                    || (window_full && rate >= self.policy.failure_rate)
+                {
+                    self.transition(&mut state, BreakerState::Open);
+                }
+            }
+            // Requests which were sent before the breaker last changed state
+            _ => {}
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.state == BreakerState::HalfOpen {
+            state.probes = state.probes.saturating_sub(1);
+        }
+    }
+
+    fn transition(&self, state: &mut State, to: BreakerState) {
+        match to {
+            BreakerState::Open => warn!(
+                "Opening circuit breaker of backend {} of {} model {}",
+                self.backend, self.service, self.model
+            ),
+            _ => info!(
+                "Circuit breaker of backend {} of {} model {} is now {}",
+                self.backend,
+                self.service,
+                self.model,
+                to.as_str()
+            ),
+        }
+        state.state = to;
+        state.probes = 0;
+        state.successes = 0;
+        match to {
+            BreakerState::Open => state.opened_at = Instant::now(),
+            BreakerState::Closed => {
+                state.consecutive_failures = 0;
+                state.window.clear();
+                state.failures = 0;
+            }
+            BreakerState::HalfOpen => {}
+        }
+        metrics::counter!(
+            "fmaas_router_circuit_breaker_transition_count",
+            "service" => self.service,
+            "model" => self.model.to_string(),
+            "backend" => self.backend.to_string(),
+            "state" => to.as_str()
+        )
+        .increment(1);
+        self.gauge(to);
+    }
+
+    fn gauge(&self, state: BreakerState) {
+        metrics::gauge!(
+            "fmaas_router_circuit_breaker_state",
+            "service" => self.service,
+            "model" => self.model.to_string(),
+            "backend" => self.backend.to_string()
+        )
+        .set(state.gauge());
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_millis(self.policy.open_ms)
+    }
+}
+
+/// A request let through by a circuit breaker, whose outcome is recorded by it.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    breaker: Arc<CircuitBreaker>,
+    /// Whether the request is a probe of a half-open breaker.
+    probe: bool,
+    done: bool,
+}
+
+impl Permit {
+    /// Records the outcome of the request, which failed if it has an `error`
+    /// with one of the failure status codes of the breaker's policy.
+    pub fn record(mut self, error: Option<&Status>) {
+        let failed = error.is_some_and(|status| {
+            self.breaker
+                .policy
+                .failure_status_codes
+                .contains(&status.code())
+        });
+        self.breaker.record(failed, self.probe);
+        self.done = true;
+    }
+}
+
+impl Drop for Permit {
+    /// Frees the probe slot of a request which was cancelled before it completed.
+    fn drop(&mut self) {
+        if !self.done && self.probe {
+            self.breaker.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn breaker(open_ms: u64) -> Arc<CircuitBreaker> {
+        let policy = CircuitBreakerPolicy {
+            consecutive_failures: 3,
+            failure_rate: 0.5,
+            window_size: 10,
+            open_ms,
+            half_open_requests: 1,
+            ..Default::default()
+        };
+        Arc::new(CircuitBreaker::new(
+            "generation",
+            "model".into(),
+            "backend".into(),
+            policy,
+        ))
+    }
+
+    fn state(breaker: &CircuitBreaker) -> BreakerState {
+        breaker.status().state
+    }
+
+    fn send(breaker: &Arc<CircuitBreaker>, code: Option<Code>) {
+        let status = code.map(|code| Status::new(code, ""));
+        breaker.permit().unwrap().record(status.as_ref());
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(60_000);
+        send(&breaker, Some(Code::Unavailable));
+        send(&breaker, Some(Code::Unavailable));
+        send(&breaker, None);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+        send(&breaker, Some(Code::Unavailable));
+        send(&breaker, Some(Code::Internal));
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        send(&breaker, Some(Code::Unavailable));
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(breaker.is_open());
+        let status = breaker.permit().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[test]
+    fn ignores_other_errors() {
+        let breaker = breaker(60_000);
+        for _ in 0..10 {
+            send(&breaker, Some(Code::InvalidArgument));
+            send(&breaker, Some(Code::DeadlineExceeded));
+        }
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert_eq!(breaker.status().failure_rate, 0.0);
+    }
+
+    #[test]
+    fn opens_on_failure_rate_once_window_is_full() {
+        let breaker = breaker(60_000);
+        for _ in 0..4 {
+            send(&breaker, Some(Code::Unavailable));
+            send(&breaker, None);
+        }
+        send(&breaker, Some(Code::Unavailable));
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        send(&breaker, None);
+        assert_eq!(state(&breaker), BreakerState::Open);
+    }
+
+    #[test]
+    fn closes_after_successful_probe() {
+        let breaker = breaker(0);
+        for _ in 0..3 {
+            send(&breaker, Some(Code::Unavailable));
+        }
+        assert_eq!(state(&breaker), BreakerState::Open);
+        let probe = breaker.permit().unwrap();
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+        assert!(breaker.is_open());
+        assert!(breaker.permit().is_err());
+        probe.record(None);
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn reopens_after_failed_probe() {
+        let breaker = breaker(60_000);
+        for _ in 0..3 {
+            send(&breaker, Some(Code::Unavailable));
+        }
+        breaker.transition(&mut breaker.state.lock().unwrap(), BreakerState::HalfOpen);
+        send(&breaker, Some(Code::Unavailable));
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(breaker.permit().is_err());
+    }
+
+    #[test]
+    fn cancelled_probe_frees_its_slot() {
+        let breaker = breaker(0);
+        for _ in 0..3 {
+            send(&breaker, Some(Code::Unavailable));
+        }
+        drop(breaker.permit().unwrap());
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+        assert!(!breaker.is_open());
+        send(&breaker, None);
+        assert_eq!(state(&breaker), BreakerState::Closed);
+    }
+}
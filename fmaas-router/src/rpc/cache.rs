@@ -0,0 +1,393 @@
+//! Caching of the responses to deterministic requests, which are returned again
+//! for identical requests without sending them upstream.
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+use tokio::time::Instant;
+use tonic::{metadata::MetadataMap, Request, Response, Status};
+use tracing::debug;
+
+use crate::{
+    rpc::{caller_metadata, CallerMetadata},
+    CachePolicy,
+};
+
+/// Name of the metadata with the caching directives of a request, which are
+/// those of the HTTP header of the same name. `no-cache` skips the lookup in the
+/// cache but caches the response, and `no-store` also doesn't cache it.
+const METADATA_NAME_CACHE_CONTROL: &str = "cache-control";
+
+/// Cache of the responses to the requests for a configured model, in one
+/// service.
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    service: &'static str,
+    /// Name the model is configured with, which labels the metrics.
+    model: Arc<str>,
+    policy: CachePolicy,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    rpc: &'static str,
+    /// Model id that the request is sent upstream for, which differs between
+    /// those matched by a pattern or the fallback.
+    model_id: String,
+    /// The encoded request message.
+    request: Vec<u8>,
+    /// Metadata of the request, so that responses are only returned to callers
+    /// with the same credentials.
+    metadata: CallerMetadata,
+}
+
+impl Key {
+    fn size(&self) -> usize {
+        let metadata: usize = self
+            .metadata
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        self.model_id.len() + self.request.len() + metadata
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<Arc<Key>, Entry>,
+    /// Keys of the entries by when they were last used, least recently first.
+    lru: BTreeMap<u64, Arc<Key>>,
+    /// Number of lookups and insertions so far, which orders the entries.
+    clock: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The encoded response.
+    response: Vec<u8>,
+    expires_at: Instant,
+    used_at: u64,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(service: &'static str, model: Arc<str>, policy: CachePolicy) -> Self {
+        Self {
+            service,
+            model,
+            policy,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Whether this is the cache of `model` with `policy`.
+    pub fn is_for(&self, model: &str, policy: &CachePolicy) -> bool {
+        &*self.model == model && &self.policy == policy
+    }
+
+    fn get(&self, key: &Key) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let entry = state.entries.get_mut(key)?;
+        if entry.expires_at <= Instant::now() {
+            let used_at = entry.used_at;
+            let key = state.lru.remove(&used_at).unwrap();
+            state.remove(&key);
+            return None;
+        }
+        state.clock += 1;
+        let key = state.lru.remove(&entry.used_at).unwrap();
+        entry.used_at = state.clock;
+        state.lru.insert(state.clock, key);
+        Some(entry.response.clone())
+    }
+
+    fn insert(&self, key: Key, response: Vec<u8>) {
+        let size = key.size() + response.len();
+        if size > self.policy.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let key = Arc::new(key);
+        if let Some(used_at) = state.entries.get(&key).map(|entry| entry.used_at) {
+            state.lru.remove(&used_at);
+            state.remove(&key);
+        }
+        state.clock += 1;
+        let used_at = state.clock;
+        state.lru.insert(used_at, key.clone());
+        state.bytes += size;
+        state.entries.insert(
+            key,
+            Entry {
+                response,
+                expires_at: Instant::now() + self.policy.ttl(),
+                used_at,
+                size,
+            },
+        );
+        while state.entries.len() > self.policy.max_entries || state.bytes > self.policy.max_bytes {
+            let (_, key) = state.lru.pop_first().unwrap();
+            state.remove(&key);
+        }
+    }
+
+    fn count(&self, name: &'static str, rpc: &'static str) {
+        metrics::counter!(
+            name,
+            "service" => self.service,
+            "rpc" => rpc,
+            "model" => self.model.to_string()
+        )
+        .increment(1);
+    }
+}
+
+impl State {
+    /// Removes the entry of `key`, which must already be gone from `lru`.
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// Caching directives of a request.
+#[derive(Debug, Default)]
+struct Directives {
+    no_cache: bool,
+    no_store: bool,
+}
+
+impl Directives {
+    fn from_metadata(metadata: &MetadataMap) -> Self {
+        let mut directives = Self::default();
+        for value in metadata.get_all(METADATA_NAME_CACHE_CONTROL) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in value.split(',') {
+                match directive.trim().to_ascii_lowercase().as_str() {
+                    "no-cache" => directives.no_cache = true,
+                    "no-store" => directives.no_store = true,
+                    _ => {}
+                }
+            }
+        }
+        directives
+    }
+}
+
+/// Copies the caching directives of a request's metadata `from` into the
+/// metadata `to` of a request which is sent upstream on its behalf.
+pub(crate) fn copy_directives(from: &MetadataMap, to: &mut MetadataMap) {
+    for value in from.get_all(METADATA_NAME_CACHE_CONTROL) {
+        to.append(METADATA_NAME_CACHE_CONTROL, value.clone());
+    }
+}
+
+/// Returns the cached response to `request` for `rpc` to `model_id` if `cache`
+/// has one, or else sends it with `send` and caches the response, unless the
+/// request's `cache-control` metadata says otherwise. Requests are sent as they
+/// are if there is no `cache` or it doesn't cache `rpc`.
+pub(crate) async fn call<T, R, F, Fut>(
+    rpc: &'static str,
+    cache: Option<Arc<ResponseCache>>,
+    model_id: &str,
+    request: Request<T>,
+    send: F,
+) -> Result<Response<R>, Status>
+where
+    T: Message,
+    R: Message + Default,
+    F: FnOnce(Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<R>, Status>>,
+{
+    let Some(cache) = cache.filter(|cache| cache.policy.caches(rpc)) else {
+        return send(request).await;
+    };
+    let directives = Directives::from_metadata(request.metadata());
+    let key = Key {
+        rpc,
+        model_id: model_id.to_string(),
+        request: request.get_ref().encode_to_vec(),
+        metadata: caller_metadata(request.metadata()),
+    };
+    if !directives.no_cache && !directives.no_store {
+        match cache.get(&key) {
+            Some(response) => {
+                // Responses are only cached after they have been encoded
+                if let Ok(response) = R::decode(response.as_slice()) {
+                    debug!("Returning cached {rpc} response for model_id {model_id}");
+                    cache.count("fmaas_router_cache_hit_count", rpc);
+                    return Ok(Response::new(response));
+                }
+            }
+            None => cache.count("fmaas_router_cache_miss_count", rpc),
+        }
+    }
+    let response = send(request).await?;
+    if !directives.no_store {
+        cache.insert(key, response.get_ref().encode_to_vec());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn cache(ttl_ms: u64, max_entries: usize, max_bytes: usize) -> ResponseCache {
+        let policy = CachePolicy {
+            rpcs: vec!["Tokenize".to_string()],
+            ttl_ms,
+            max_entries,
+            max_bytes,
+        };
+        ResponseCache::new("generation", "model".into(), policy)
+    }
+
+    fn key(request: &str) -> Key {
+        Key {
+            rpc: "Tokenize",
+            model_id: "model".to_string(),
+            request: request.as_bytes().to_vec(),
+            metadata: vec![],
+        }
+    }
+
+    fn bytes(cache: &ResponseCache) -> usize {
+        cache.state.lock().unwrap().bytes
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire_after_ttl() {
+        let cache = cache(1000, 10, 1 << 20);
+        cache.insert(key("a"), b"response".to_vec());
+        tokio::time::advance(Duration::from_millis(999)).await;
+        assert_eq!(cache.get(&key("a")), Some(b"response".to_vec()));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(cache.get(&key("a")), None);
+        let state = cache.state.lock().unwrap();
+        assert!(state.entries.is_empty() && state.lru.is_empty());
+        assert_eq!(state.bytes, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_least_recently_used_entries() {
+        let cache = cache(60_000, 2, 1 << 20);
+        cache.insert(key("a"), b"1".to_vec());
+        cache.insert(key("b"), b"2".to_vec());
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), b"3".to_vec());
+        assert_eq!(cache.get(&key("b")), None);
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+        assert_eq!(bytes(&cache), 2 * (key("a").size() + 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_entries_beyond_max_bytes() {
+        // Each entry is 5 bytes of model id, 1 of request and 4 of response
+        let cache = cache(60_000, 10, 25);
+        cache.insert(key("a"), b"1111".to_vec());
+        cache.insert(key("b"), b"2222".to_vec());
+        cache.insert(key("c"), b"3333".to_vec());
+        assert_eq!(cache.get(&key("a")), None);
+        assert_eq!(bytes(&cache), 20);
+        // Responses which would never fit aren't cached
+        cache.insert(key("d"), vec![0; 32]);
+        assert_eq!(cache.get(&key("d")), None);
+        assert!(cache.get(&key("b")).is_some());
+        // Replacing an entry doesn't count it twice
+        cache.insert(key("b"), b"22".to_vec());
+        assert_eq!(bytes(&cache), 18);
+        assert_eq!(cache.get(&key("b")), Some(b"22".to_vec()));
+    }
+
+    async fn call_cached(
+        cache: &Arc<ResponseCache>,
+        rpc: &'static str,
+        cache_control: Option<&'static str>,
+        response: &str,
+    ) -> String {
+        call_as(cache, rpc, cache_control, "Bearer a", response).await
+    }
+
+    async fn call_as(
+        cache: &Arc<ResponseCache>,
+        rpc: &'static str,
+        cache_control: Option<&'static str>,
+        authorization: &'static str,
+        response: &str,
+    ) -> String {
+        let mut request = Request::new("request".to_string());
+        request
+            .metadata_mut()
+            .insert("authorization", authorization.parse().unwrap());
+        if let Some(value) = cache_control {
+            request
+                .metadata_mut()
+                .insert(METADATA_NAME_CACHE_CONTROL, value.parse().unwrap());
+        }
+        let response = response.to_string();
+        call(rpc, Some(cache.clone()), "model", request, |_| async move {
+            Ok(Response::new(response))
+        })
+        .await
+        .unwrap()
+        .into_inner()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_follows_directives() {
+        let cache = Arc::new(cache(60_000, 10, 1 << 20));
+        assert_eq!(
+            call_cached(&cache, "Tokenize", Some("no-store"), "0").await,
+            "0"
+        );
+        assert_eq!(call_cached(&cache, "Tokenize", None, "1").await, "1");
+        assert_eq!(call_cached(&cache, "Tokenize", None, "2").await, "1");
+        assert_eq!(
+            call_cached(&cache, "Tokenize", Some("no-cache"), "3").await,
+            "3"
+        );
+        assert_eq!(call_cached(&cache, "Tokenize", None, "4").await, "3");
+        assert_eq!(
+            call_cached(&cache, "Tokenize", Some("max-age=0, No-Store"), "5").await,
+            "5"
+        );
+        assert_eq!(call_cached(&cache, "Tokenize", None, "6").await, "3");
+        // Other RPCs aren't cached
+        assert_eq!(call_cached(&cache, "Generate", None, "7").await, "7");
+        assert_eq!(call_cached(&cache, "Generate", None, "8").await, "8");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn responses_are_only_shared_with_the_same_metadata() {
+        let cache = Arc::new(cache(60_000, 10, 1 << 20));
+        assert_eq!(
+            call_as(&cache, "Tokenize", None, "Bearer a", "1").await,
+            "1"
+        );
+        assert_eq!(
+            call_as(&cache, "Tokenize", None, "Bearer b", "2").await,
+            "2"
+        );
+        assert_eq!(
+            call_as(&cache, "Tokenize", None, "Bearer a", "3").await,
+            "1"
+        );
+        assert_eq!(
+            call_as(&cache, "Tokenize", None, "Bearer b", "4").await,
+            "2"
+        );
+    }
+}
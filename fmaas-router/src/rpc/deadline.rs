@@ -0,0 +1,185 @@
+//! Deadlines of requests, which are either given by clients in `grpc-timeout` or
+//! configured per model and RPC, and are propagated to upstreams.
+use std::{future::Future, marker::PhantomData, time::Duration};
+
+use futures::{stream::BoxStream, StreamExt};
+use tokio::time::Instant;
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    Request, Response, Status,
+};
+use tracing::warn;
+
+use crate::clients::Route;
+
+/// Name of the metadata set on `DEADLINE_EXCEEDED` errors which were returned by
+/// the router itself rather than by an upstream.
+pub(crate) const METADATA_NAME_ROUTER_DEADLINE: &str = "fmaas-router-deadline-exceeded";
+
+const METADATA_NAME_GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Deadline of a request to clients of type `C`, before which the router must
+/// have responded.
+#[derive(Debug, Clone)]
+pub(crate) struct Deadline<C> {
+    rpc: &'static str,
+    model_id: String,
+    /// Instant by which upstreams must have responded, if the request has a
+    /// deadline.
+    at: Option<Instant>,
+    timeout: Duration,
+    /// Type of the clients of the route, so that it needn't be given for the
+    /// calls that are wrapped by [`Self::propagate`].
+    client: PhantomData<fn(C)>,
+}
+
+impl<C: Clone + 'static> Deadline<C> {
+    /// Deadline of a request for `rpc` to `route` with `metadata`, which was
+    /// received at `received`. This is the client's deadline less the margin of
+    /// the route if the request has a `grpc-timeout`, or else the default
+    /// deadline of the route for `rpc` (if any).
+    pub fn new(
+        rpc: &'static str,
+        route: &Route<C>,
+        metadata: &MetadataMap,
+        received: Instant,
+    ) -> Self {
+        let timeout = match client_timeout(metadata) {
+            Some(timeout) => Some(timeout.saturating_sub(route.deadline_margin)),
+            None => route.deadlines.get(rpc),
+        };
+        Self {
+            rpc,
+            model_id: route.model_id.clone(),
+            at: timeout.map(|timeout| received + timeout),
+            timeout: timeout.unwrap_or_default(),
+            client: PhantomData,
+        }
+    }
+
+    /// Deadline at `at` of a request for `rpc` to `route` which is made on behalf
+    /// of other requests, such as a batch of them, whose deadlines were already
+    /// worked out.
+    pub fn until(rpc: &'static str, route: &Route<C>, at: Option<Instant>) -> Self {
+        let timeout = at.map(|at| at.saturating_duration_since(Instant::now()));
+        Self {
+            rpc,
+            model_id: route.model_id.clone(),
+            at,
+            timeout: timeout.unwrap_or_default(),
+            client: PhantomData,
+        }
+    }
+
+    /// Instant by which upstreams must have responded, if the request has a
+    /// deadline.
+    pub fn at(&self) -> Option<Instant> {
+        self.at
+    }
+
+    /// Wraps `call` to set the `grpc-timeout` of each request it sends upstream
+    /// to the time left until the deadline, so that retries and failovers only
+    /// get what is left of it.
+    pub fn propagate<T, F, Fut>(&self, call: F) -> impl Fn(C, Request<T>) -> Fut
+    where
+        F: Fn(C, Request<T>) -> Fut,
+    {
+        let at = self.at;
+        move |client, mut request| {
+            if let Some(at) = at {
+                request.set_timeout(at.saturating_duration_since(Instant::now()));
+            }
+            call(client, request)
+        }
+    }
+
+    /// Awaits `response`, failing with `DEADLINE_EXCEEDED` if it isn't ready by
+    /// the deadline. Upstream errors at the deadline, which are usually upstreams
+    /// timing out themselves, are replaced by the router's.
+    pub async fn run<R>(
+        self,
+        response: impl Future<Output = Result<R, Status>>,
+    ) -> Result<R, Status> {
+        let Some(at) = self.at else {
+            return response.await;
+        };
+        match tokio::time::timeout_at(at, response).await {
+            Ok(Err(_)) if Instant::now() >= at => Err(self.exceeded()),
+            Ok(result) => result,
+            Err(_) => Err(self.exceeded()),
+        }
+    }
+
+    /// Awaits a streaming `response` as [`Self::run`] does, and also ends the
+    /// stream with `DEADLINE_EXCEEDED` if it is still open at the deadline.
+    pub async fn run_stream<M: Send + 'static>(
+        self,
+        response: impl Future<Output = Result<Response<BoxStream<'static, Result<M, Status>>>, Status>>,
+    ) -> Result<Response<BoxStream<'static, Result<M, Status>>>, Status> {
+        let Some(at) = self.at else {
+            return response.await;
+        };
+        let response = self.clone().run(response).await?;
+        Ok(response.map(|stream| {
+            futures::stream::unfold(Some(stream), move |stream| {
+                let deadline = self.clone();
+                async move {
+                    let mut stream = stream?;
+                    match tokio::time::timeout_at(at, stream.next()).await {
+                        Ok(Some(Err(_))) if Instant::now() >= at => {
+                            Some((Err(deadline.exceeded()), None))
+                        }
+                        Ok(Some(message)) => Some((message, Some(stream))),
+                        Ok(None) => None,
+                        Err(_) => Some((Err(deadline.exceeded()), None)),
+                    }
+                }
+            })
+            .boxed()
+        }))
+    }
+
+    fn exceeded(&self) -> Status {
+        warn!(
+            "{} request for model_id {} exceeded its deadline of {}ms",
+            self.rpc,
+            self.model_id,
+            self.timeout.as_millis()
+        );
+        metrics::counter!(
+            "fmaas_router_deadline_exceeded_count",
+            "rpc" => self.rpc,
+            "model" => self.model_id.clone()
+        )
+        .increment(1);
+        let mut status = Status::deadline_exceeded(format!(
+            "Deadline of {}ms exceeded in router",
+            self.timeout.as_millis()
+        ));
+        status.metadata_mut().insert(
+            METADATA_NAME_ROUTER_DEADLINE,
+            MetadataValue::from_static("true"),
+        );
+        status
+    }
+}
+
+/// Timeout of a request given by the client in `grpc-timeout`, which is at most
+/// 8 digits followed by a unit.
+pub(crate) fn client_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let value = metadata.get(METADATA_NAME_GRPC_TIMEOUT)?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
@@ -0,0 +1,193 @@
+//! Failover of requests to the failover backends of a model, in order, when the
+//! backend they were sent to is unavailable or its circuit breaker is open.
+use std::{future::Future, sync::Arc};
+
+use futures::{stream::BoxStream, Stream, StreamExt};
+use tonic::{metadata::MetadataMap, Code, Extensions, Request, Response, Status};
+use tracing::{warn, Span};
+
+use crate::{
+    clients::Route,
+    rpc::{
+        breaker::{Breakers, Permit},
+        deadline::client_timeout,
+    },
+};
+
+/// Sends `request` with `call` to the client of `route`, retrying it on the
+/// failover backends of `route` in order while it fails with `UNAVAILABLE`.
+/// Outcomes are recorded by the circuit breakers of the backends, which reject
+/// requests with `UNAVAILABLE` while they are open.
+pub(crate) async fn call<C, T, R, F, Fut>(
+    route: Route<C>,
+    request: Request<T>,
+    call: F,
+) -> Result<Response<R>, Status>
+where
+    T: Clone,
+    F: Fn(C, Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<R>, Status>>,
+{
+    if route.failover.is_empty() && route.breakers.is_empty() {
+        return call(route.client, request).await;
+    }
+    let mut attempts = Attempts::new(route, request);
+    loop {
+        let (client, request) = attempts.next();
+        let permit = match attempts.permit() {
+            Ok(permit) => permit,
+            Err(status) if attempts.fail_over(&status) => continue,
+            Err(status) => return Err(status),
+        };
+        let result = call(client, request).await;
+        attempts.record(permit, result.as_ref().err());
+        match result {
+            Err(status) if attempts.fail_over(&status) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Sends a streaming `request` with `call` as [`call`] does, but also fails over
+/// if the response stream fails with `UNAVAILABLE` before its first message, so
+/// that the messages a client receives all come from the same backend.
+pub(crate) async fn call_stream<C, T, M, S, F, Fut>(
+    route: Route<C>,
+    request: Request<T>,
+    call: F,
+) -> Result<Response<BoxStream<'static, Result<M, Status>>>, Status>
+where
+    T: Clone,
+    M: Send + 'static,
+    S: Stream<Item = Result<M, Status>> + Send + Unpin + 'static,
+    F: Fn(C, Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<S>, Status>>,
+{
+    if route.failover.is_empty() && route.breakers.is_empty() {
+        return call(route.client, request)
+            .await
+            .map(|response| response.map(StreamExt::boxed));
+    }
+    let mut attempts = Attempts::new(route, request);
+    loop {
+        let (client, request) = attempts.next();
+        let permit = match attempts.permit() {
+            Ok(permit) => permit,
+            Err(status) if attempts.fail_over(&status) => continue,
+            Err(status) => return Err(status),
+        };
+        let (metadata, mut stream, extensions) = match call(client, request).await {
+            Ok(response) => response.into_parts(),
+            Err(status) => {
+                attempts.record(permit, Some(&status));
+                match attempts.fail_over(&status) {
+                    true => continue,
+                    false => return Err(status),
+                }
+            }
+        };
+        // The outcome of a stream is judged by its first message
+        let first = stream.next().await;
+        attempts.record(
+            permit,
+            first.as_ref().and_then(|first| first.as_ref().err()),
+        );
+        let first = match first {
+            Some(Err(status)) if attempts.fail_over(&status) => continue,
+            first => first,
+        };
+        let stream = futures::stream::iter(first).chain(stream).boxed();
+        return Ok(Response::from_parts(metadata, stream, extensions));
+    }
+}
+
+/// The backends a request is sent to in turn, along with what is needed to
+/// send it again.
+struct Attempts<C, T> {
+    model_id: String,
+    /// Address of the backend of the current attempt.
+    backend: Arc<str>,
+    /// Backends which haven't been tried yet, in reverse order.
+    remaining: Vec<(Arc<str>, C)>,
+    breakers: Arc<Breakers>,
+    metadata: MetadataMap,
+    message: T,
+}
+
+impl<C, T: Clone> Attempts<C, T> {
+    fn new(route: Route<C>, request: Request<T>) -> Self {
+        let (metadata, _, message) = request.into_parts();
+        let mut remaining = vec![(route.backend.clone(), route.client)];
+        remaining.extend(route.failover);
+        remaining.reverse();
+        Self {
+            model_id: route.model_id,
+            backend: route.backend,
+            remaining,
+            breakers: route.breakers,
+            metadata,
+            message,
+        }
+    }
+
+    /// The client of the next backend and the request to send to it.
+    fn next(&mut self) -> (C, Request<T>) {
+        let (backend, client) = self.remaining.pop().unwrap();
+        self.backend = backend;
+        let request = Request::from_parts(
+            self.metadata.clone(),
+            Extensions::default(),
+            self.message.clone(),
+        );
+        (client, request)
+    }
+
+    /// Lets the request through the circuit breaker of the current backend, if it
+    /// has one.
+    fn permit(&self) -> Result<Option<Permit>, Status> {
+        self.breakers
+            .get(&self.backend)
+            .map(|breaker| breaker.permit())
+            .transpose()
+    }
+
+    /// Records the outcome of an attempt with the circuit breaker which let it
+    /// through, if any. Missing a deadline set by the client isn't held against
+    /// the backend, since clients can set deadlines which no backend could meet.
+    fn record(&self, permit: Option<Permit>, error: Option<&Status>) {
+        let Some(permit) = permit else {
+            return;
+        };
+        match error {
+            Some(status)
+                if status.code() == Code::DeadlineExceeded
+                    && client_timeout(&self.metadata).is_some() => {}
+            error => permit.record(error),
+        }
+    }
+
+    /// Whether to retry the request on the next backend after it failed with
+    /// `status`.
+    fn fail_over(&self, status: &Status) -> bool {
+        if status.code() != Code::Unavailable {
+            return false;
+        }
+        let Some((next, _)) = self.remaining.last() else {
+            return false;
+        };
+        warn!(
+            "Backend {} of model_id {} is unavailable, failing over to {next}: {}",
+            self.backend,
+            self.model_id,
+            status.message()
+        );
+        metrics::counter!(
+            "fmaas_router_failover_count",
+            "model" => self.model_id.clone(),
+            "backend" => next.to_string()
+        )
+        .increment(1);
+        Span::current().record("backend", &**next);
+        true
+    }
+}
@@ -1,42 +1,103 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use ginepro::LoadBalancedChannel;
-use tonic::{transport::ClientTlsConfig, Request, Response, Status, Streaming};
-use tracing::{debug, instrument};
+use futures::stream::BoxStream;
+use prost::Message;
+use tokio::time::Instant;
+use tonic::{metadata::MetadataMap, Extensions, Request, Response, Status};
+use tracing::{debug, instrument, Instrument, Span};
 
 use crate::{pb::fmaas::{
     generation_service_client::GenerationServiceClient,
     generation_service_server::GenerationService, BatchedGenerationRequest,
-    BatchedGenerationResponse, BatchedTokenizeRequest, BatchedTokenizeResponse,
-    GenerationResponse, ModelInfoRequest, ModelInfoResponse, SingleGenerationRequest,
-}, create_clients, ServiceAddr, tracing_utils::{ExtractTelemetryContext, InjectTelemetryContext}};
+    BatchedGenerationResponse, BatchedTokenizeRequest, BatchedTokenizeResponse, DecodingMethod,
+    GenerationRequest, GenerationResponse, ModelInfoRequest, ModelInfoResponse, SingleGenerationRequest,
+}, clients::{ModelClients, Route, UpstreamChannel}, rpc::{batcher::Batcher, cache, caller_metadata, deadline::Deadline, failover, retry, shadow, singleflight::SingleFlight, split, CallerMetadata}, tracing_utils::{ExtractTelemetryContext, InjectTelemetryContext}};
 
-#[derive(Debug, Default)]
+/// Key of the single generation requests which can be coalesced into a batch,
+/// which is their upstream model id, `adapter_id`, `prefix_id`, encoded `params`
+/// and caller metadata.
+type CoalesceKey = (String, Option<String>, Option<String>, Option<Vec<u8>>, CallerMetadata);
+
+#[derive(Debug)]
 pub struct GenerationServicer {
-    clients: HashMap<String, GenerationServiceClient<LoadBalancedChannel>>,
+    clients: Arc<ModelClients<GenerationServiceClient<UpstreamChannel>>>,
+    /// Single generation requests waiting to be sent upstream together.
+    generations: Arc<Batcher<CoalesceKey, GenerationRequest, GenerationResponse>>,
+    /// Identical requests in flight, which share their upstream calls.
+    in_flight: SingleFlight,
 }
 
 impl GenerationServicer {
-    pub async fn new(
-        default_target_port: u16,
-        client_tls: Option<&ClientTlsConfig>,
-        model_map: &HashMap<String, ServiceAddr>,
-    ) -> Self {
-        let clients = create_clients(
-            default_target_port, client_tls, model_map, GenerationServiceClient::new
-        ).await;
-        Self { clients }
+    pub fn new(clients: Arc<ModelClients<GenerationServiceClient<UpstreamChannel>>>) -> Self {
+        Self {
+            clients,
+            generations: Arc::new(Batcher::new("Generate")),
+            in_flight: SingleFlight::new("generation"),
+        }
+    }
+
+    /// Resolves the route for `model_id` and `adapter_id` (if any), replacing
+    /// `model_id` with the model id to send upstream, and records the chosen
+    /// backend in `span`.
+    async fn route(
+        &self,
+        model_id: &mut String,
+        adapter_id: Option<&str>,
+        span: &Span,
+    ) -> Result<Route<GenerationServiceClient<UpstreamChannel>>, Status> {
+        let route = self.clients.route_adapter(model_id, adapter_id)?;
+        span.record("backend", &*route.backend);
+        model_id.clone_from(&route.model_id);
+        Ok(route)
     }
 
-    async fn client(
+    /// Sends the generation `request` to `route`, coalescing it with other single
+    /// requests or splitting it into sub-batches if the model is configured to.
+    async fn send_generate(
         &self,
-        model_id: &str,
-    ) -> Result<GenerationServiceClient<LoadBalancedChannel>, Status> {
-        Ok(self
-            .clients
-            .get(model_id)
-            .ok_or_else(|| Status::not_found(format!("Unrecognized model_id: {model_id}")))?
-            .clone())
+        route: Route<GenerationServiceClient<UpstreamChannel>>,
+        mut request: Request<BatchedGenerationRequest>,
+        deadline: Deadline<GenerationServiceClient<UpstreamChannel>>,
+    ) -> Result<Response<BatchedGenerationResponse>, Status> {
+        let call = deadline.propagate(|mut client, request| async move {
+            client.generate(request).await
+        });
+        let len = request.get_ref().requests.len();
+        if let Some(micro_batching) = route.micro_batching.clone().filter(|_| len == 1) {
+            let (metadata, _, mut br) = request.into_parts();
+            let item = br.requests.pop().unwrap();
+            let key = (
+                br.model_id.clone(),
+                br.adapter_id.clone(),
+                br.prefix_id.clone(),
+                br.params.as_ref().map(Message::encode_to_vec),
+                caller_metadata(&metadata),
+            );
+            let model_id = route.model_id.clone();
+            let send = move |requests, at| {
+                generate_batch(route, metadata, BatchedGenerationRequest { requests, ..br }, at)
+            };
+            let at = deadline.at();
+            let response = self.generations.submit(key, &model_id, &micro_batching, item, at, send);
+            return deadline
+                .run(response)
+                .await
+                .map(|response| Response::new(BatchedGenerationResponse {
+                    responses: vec![response],
+                }));
+        }
+        if let Some(batch_split) = route.batch_split.clone().filter(|s| len > s.max_batch_size) {
+            // Each sub-batch has the affinity key of its own first prompt
+            let send = |route: Route<_>, mut request: Request<BatchedGenerationRequest>| {
+                route.set_affinity_key(&mut request, |br| &br.requests[0].text);
+                shadow::call("Generate", route, request, &call)
+            };
+            return deadline
+                .run(split::call("Generate", route, request, &batch_split, send))
+                .await;
+        }
+        route.set_affinity_key(&mut request, |br| &br.requests[0].text);
+        deadline.run(shadow::call("Generate", route, request, call)).await
     }
 }
 
@@ -44,8 +105,9 @@ impl GenerationServicer {
 impl GenerationService for GenerationServicer {
     async fn generate(
         &self,
-        request: Request<BatchedGenerationRequest>,
+        mut request: Request<BatchedGenerationRequest>,
     ) -> Result<Response<BatchedGenerationResponse>, Status> {
+        let received = Instant::now();
         let br = request.get_ref();
         if br.requests.is_empty() {
             return Ok(Response::new(BatchedGenerationResponse {
@@ -53,27 +115,41 @@ impl GenerationService for GenerationServicer {
             }));
         }
         debug!("Routing generation request for Model ID {}", &br.model_id);
-        let mut client = self.client(&br.model_id).await?;
         let mut span = tracing::info_span!(
             "fmaas.GenerationService/Generate",
             rpc.system = "grpc",
             rpc.method = "Generate",
             rpc.service = "GenerationService",
-            model_id = br.model_id
+            model_id = br.model_id,
+            backend = tracing::field::Empty
         );
+        let br = request.get_mut();
+        normalize_adapter_id(&mut br.adapter_id, &br.prefix_id);
+        let mut route = self.route(&mut br.model_id, br.adapter_id.as_deref(), &span).await?;
+        // Generation isn't idempotent, so it is only failed over but never retried
+        route.retry = None;
         // Extract span info from the request metadata and set to current span
         let request = request
             .extract_context_span(&mut span)
             .inject_context_span(&span); // Inject span info into request metadata
-        client.generate(request).await
+        let deadline = Deadline::new("Generate", &route, request.metadata(), received);
+        // Only greedy decoding without a seed is deterministic
+        let cache = route.cache.clone().filter(|_| is_greedy(request.get_ref()));
+        let model_id = route.model_id.clone();
+        cache::call("Generate", cache, &model_id, request, |request| {
+            self.send_generate(route, request, deadline)
+        })
+        .instrument(span)
+        .await
     }
 
-    type GenerateStreamStream = Streaming<GenerationResponse>;
+    type GenerateStreamStream = BoxStream<'static, Result<GenerationResponse, Status>>;
 
     async fn generate_stream(
         &self,
-        request: Request<SingleGenerationRequest>,
+        mut request: Request<SingleGenerationRequest>,
     ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let received = Instant::now();
         let sr = request.get_ref();
         if sr.request.is_none() {
             return Err(Status::invalid_argument("missing request"));
@@ -82,45 +158,122 @@ impl GenerationService for GenerationServicer {
             "Routing streaming generation request for Model ID {}",
             &sr.model_id
         );
-        let mut client = self.client(&sr.model_id).await?;
         let mut span = tracing::info_span!(
             "fmaas.GenerationService/GenerateStream",
             rpc.system = "grpc",
             rpc.method = "GenerateStream",
             rpc.service = "GenerationService",
-            model_id = sr.model_id
+            model_id = sr.model_id,
+            backend = tracing::field::Empty
         );
-        let request = request
+        let sr = request.get_mut();
+        normalize_adapter_id(&mut sr.adapter_id, &sr.prefix_id);
+        let route = self.route(&mut sr.model_id, sr.adapter_id.as_deref(), &span).await?;
+        let mut request = request
             .extract_context_span(&mut span)
             .inject_context_span(&span);
-        client.generate_stream(request).await
+        route.set_affinity_key(&mut request, |sr| {
+            sr.request.as_ref().map_or("", |request| &request.text)
+        });
+        let deadline = Deadline::new("GenerateStream", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.generate_stream(request).await
+        });
+        deadline
+            .run_stream(failover::call_stream(route, request, call))
+            .instrument(span)
+            .await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn tokenize(
         &self,
-        request: Request<BatchedTokenizeRequest>,
+        mut request: Request<BatchedTokenizeRequest>,
     ) -> Result<Response<BatchedTokenizeResponse>, Status> {
+        let received = Instant::now();
         let br = request.get_ref();
         if br.requests.is_empty() {
             return Ok(Response::new(BatchedTokenizeResponse { responses: vec![] }));
         }
         debug!("Routing tokenization request for Model ID {}", &br.model_id);
-        self.client(&br.model_id).await?.tokenize(request).await
+        let route = self
+            .route(&mut request.get_mut().model_id, None, &Span::current())
+            .await?;
+        let deadline = Deadline::new("Tokenize", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.tokenize(request).await
+        });
+        let (cache, model_id) = (route.cache.clone(), route.model_id.clone());
+        let single_flight = route.single_flight.clone();
+        let send = |request| shadow::call("Tokenize", route, request, call);
+        cache::call("Tokenize", cache, &model_id, request, |request| {
+            let single_flight = single_flight.as_deref();
+            deadline.run(self.in_flight.call("Tokenize", single_flight, &model_id, request, send))
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn model_info(
         &self,
-        request: Request<ModelInfoRequest>,
+        mut request: Request<ModelInfoRequest>,
     ) -> Result<Response<ModelInfoResponse>, Status> {
+        let received = Instant::now();
         debug!(
             "Routing model info request for Model ID {}",
             &request.get_ref().model_id
         );
-        self.client(&request.get_ref().model_id)
-            .await?
-            .model_info(request)
-            .await
+        let route = self
+            .route(&mut request.get_mut().model_id, None, &Span::current())
+            .await?;
+        let deadline = Deadline::new("ModelInfo", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.model_info(request).await
+        });
+        let (cache, model_id) = (route.cache.clone(), route.model_id.clone());
+        cache::call("ModelInfo", cache, &model_id, request, |request| {
+            deadline.run(retry::call(route, request, call))
+        })
+        .await
+    }
+}
+
+/// Sends the single generation requests coalesced into `br` to the backends of
+/// `route` as one batch, with the `metadata` of the request which started it,
+/// which all of them share, and by the deadline `at` (if any), returning their
+/// responses in order.
+async fn generate_batch(
+    route: Route<GenerationServiceClient<UpstreamChannel>>,
+    metadata: MetadataMap,
+    br: BatchedGenerationRequest,
+    at: Option<Instant>,
+) -> Result<Vec<GenerationResponse>, Status> {
+    let mut request = Request::from_parts(metadata, Extensions::default(), br);
+    route.set_affinity_key(&mut request, |br| &br.requests[0].text);
+    let deadline = Deadline::until("Generate", &route, at);
+    let call = deadline.propagate(|mut client, request| async move {
+        client.generate(request).await
+    });
+    let response = deadline
+        .run(shadow::call("Generate", route, request, call))
+        .await?;
+    Ok(response.into_inner().responses)
+}
+
+/// Whether a generation request uses greedy decoding without a seed, whose
+/// responses are deterministic.
+fn is_greedy(br: &BatchedGenerationRequest) -> bool {
+    br.params.as_ref().map_or(true, |params| {
+        params.method == DecodingMethod::Greedy as i32
+            && params.sampling.as_ref().map_or(true, |sampling| sampling.seed.is_none())
+    })
+}
+
+/// Copies the deprecated `prefix_id` of a generation request into its
+/// `adapter_id` if it doesn't have one, for upstreams which only understand the
+/// latter. The `prefix_id` is kept for those which only understand the former.
+fn normalize_adapter_id(adapter_id: &mut Option<String>, prefix_id: &Option<String>) {
+    if let (None | Some(""), Some(prefix_id)) = (adapter_id.as_deref(), prefix_id) {
+        *adapter_id = Some(prefix_id.clone());
     }
 }
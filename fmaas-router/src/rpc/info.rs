@@ -1,56 +1,51 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use ginepro::LoadBalancedChannel;
-use tonic::{transport::ClientTlsConfig, Request, Response, Status};
-use tracing::{debug, instrument};
+use tokio::time::Instant;
+use tonic::{Request, Response, Status};
+use tracing::{debug, instrument, Span};
 
-use crate::{create_clients, pb::{
+use crate::{clients::{ModelClients, Route, UpstreamChannel}, rpc::{cache, deadline::Deadline, retry}, pb::{
     caikit::runtime::info::{
         info_service_client::InfoServiceClient, info_service_server::InfoService
     },
     caikit_data_model::common::runtime::{
         ModelInfoRequest, ModelInfoResponse, RuntimeInfoRequest, RuntimeInfoResponse
     }
-}, ServiceAddr};
+}};
 
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct InfoServicer {
-    clients: HashMap<String, InfoServiceClient<LoadBalancedChannel>>,
+    clients: Arc<ModelClients<InfoServiceClient<UpstreamChannel>>>,
 }
 
 impl InfoServicer {
-    pub async fn new(
-        default_target_port: u16,
-        client_tls: Option<&ClientTlsConfig>,
-        model_map: &HashMap<String, ServiceAddr>,
-    ) -> Self {
-        let clients = create_clients(
-            default_target_port, client_tls, model_map, InfoServiceClient::new
-        ).await;
+    pub fn new(clients: Arc<ModelClients<InfoServiceClient<UpstreamChannel>>>) -> Self {
         Self { clients }
     }
 
+    /// Resolves the route for `model_id`, and records the chosen backend in the
+    /// current span.
     async fn client(
         &self,
         model_id: &str,
-    ) -> Result<InfoServiceClient<LoadBalancedChannel>, Status> {
-        Ok(self
-            .clients
-            .get(model_id)
-            .ok_or_else(|| Status::not_found(format!("Unrecognized model_id: {model_id}")))?
-            .clone())
+    ) -> Result<Route<InfoServiceClient<UpstreamChannel>>, Status> {
+        let route = self.clients.route(model_id)?;
+        Span::current().record("backend", &*route.backend);
+        Ok(route)
     }
 }
 
 #[tonic::async_trait]
 impl InfoService for InfoServicer {
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn get_models_info(
         &self,
         request: Request<ModelInfoRequest>,
     ) -> Result<Response<ModelInfoResponse>, Status> {
+        let received = Instant::now();
         let mir: &ModelInfoRequest = request.get_ref();
+        let metadata = request.metadata();
 
         if mir.model_ids.is_empty() {
             return Ok(Response::new(ModelInfoResponse::default()));
@@ -63,10 +58,21 @@ impl InfoService for InfoServicer {
                 "Routing get models info request for Model ID {}",
                 model
             );
-            let request = tonic::Request::new(ModelInfoRequest {model_ids: vec![model.to_string()]});
-            let mut client = self.client(model.as_str()).await?;
+            let route = self.client(model.as_str()).await?;
+            let mut request = tonic::Request::new(ModelInfoRequest {model_ids: vec![route.model_id.clone()]});
+            cache::copy_directives(metadata, request.metadata_mut());
 
-            results.push(client.get_models_info(request).await?);
+            // Deadlines run from when the request was received, so that they
+            // cover the requests for all of the models
+            let deadline = Deadline::new("GetModelsInfo", &route, metadata, received);
+            let call = deadline.propagate(|mut client, request| async move {
+                client.get_models_info(request).await
+            });
+            let (cache, model_id) = (route.cache.clone(), route.model_id.clone());
+            let response = cache::call("GetModelsInfo", cache, &model_id, request, |request| {
+                deadline.run(retry::call(route, request, call))
+            });
+            results.push(response.await?);
         }
 
        let mut models_responses = vec![];
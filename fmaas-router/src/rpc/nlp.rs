@@ -1,12 +1,13 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use ginepro::LoadBalancedChannel;
-use tonic::{transport::ClientTlsConfig, Request, Response, Status, Streaming};
-use tracing::{debug, instrument};
+use tokio::time::Instant;
+use tonic::{metadata::MetadataMap, Extensions, Request, Response, Status, Streaming};
+use tracing::{debug, instrument, Span};
 
-use crate::rpc::extract_model_id;
+use crate::rpc::{batcher::Batcher, cache, caller_metadata, deadline::Deadline, extract_model_id, retry, shadow, singleflight::SingleFlight, split, CallerMetadata, METADATA_NAME_MODEL_ID};
+use crate::tracing_utils::InjectTelemetryContext;
 
-use crate::{create_clients, pb::{
+use crate::{clients::{ModelClients, Route, UpstreamChannel}, pb::{
     caikit::runtime::nlp::{
         nlp_service_client::NlpServiceClient, nlp_service_server::NlpService, BidiStreamingTokenClassificationTaskRequest, EmbeddingTaskRequest, EmbeddingTasksRequest, RerankTaskRequest, RerankTasksRequest, SentenceSimilarityTaskRequest, SentenceSimilarityTasksRequest, ServerStreamingTextGenerationTaskRequest, TextClassificationTaskRequest, TextGenerationTaskRequest, TokenClassificationTaskRequest, TokenizationTaskRequest
     },
@@ -21,44 +22,58 @@ use crate::{create_clients, pb::{
             TokenizationResults
         },
     },
-}, ServiceAddr};
+}};
 
-#[derive(Debug, Default)]
+/// Texts of single embeddings requests waiting to be sent upstream together, by
+/// upstream model id, `truncate_input_tokens` and caller metadata.
+type EmbeddingsBatcher = Batcher<(String, Option<i64>, CallerMetadata), String, EmbeddingResult>;
+
+#[derive(Debug)]
 pub struct NlpServicer {
-    clients: HashMap<String, NlpServiceClient<LoadBalancedChannel>>,
+    clients: Arc<ModelClients<NlpServiceClient<UpstreamChannel>>>,
+    embeddings: Arc<EmbeddingsBatcher>,
+    /// Identical requests in flight, which share their upstream calls.
+    in_flight: SingleFlight,
 }
 
 impl NlpServicer {
-    pub async fn new(
-        default_target_port: u16,
-        client_tls: Option<&ClientTlsConfig>,
-        model_map: &HashMap<String, ServiceAddr>,
-    ) -> Self {
-        let clients = create_clients(
-            default_target_port, client_tls, model_map, NlpServiceClient::new
-        ).await;
-        Self { clients }
+    pub fn new(clients: Arc<ModelClients<NlpServiceClient<UpstreamChannel>>>) -> Self {
+        Self {
+            clients,
+            embeddings: Arc::new(Batcher::new("EmbeddingTaskPredict")),
+            in_flight: SingleFlight::new("nlp"),
+        }
     }
 
-    async fn client(
+    /// Resolves the route for the model id in the request metadata, replacing it
+    /// with the model id to send upstream, and records the chosen backend in the
+    /// current span.
+    async fn route<T>(
         &self,
-        model_id: &str,
-    ) -> Result<NlpServiceClient<LoadBalancedChannel>, Status> {
-        Ok(self
-            .clients
-            .get(model_id)
-            .ok_or_else(|| Status::not_found(format!("Unrecognized model_id: {model_id}")))?
-            .clone())
+        request: &mut Request<T>,
+    ) -> Result<Route<NlpServiceClient<UpstreamChannel>>, Status> {
+        let model_id = extract_model_id(request)?;
+        let route = self.clients.route(model_id)?;
+        Span::current().record("backend", &*route.backend);
+        if route.model_id != model_id {
+            let value = route
+                .model_id
+                .parse()
+                .map_err(|_| Status::internal("Invalid upstream model ID"))?;
+            request.metadata_mut().insert(METADATA_NAME_MODEL_ID, value);
+        }
+        Ok(route)
     }
 }
 
 #[tonic::async_trait]
 impl NlpService for NlpServicer {
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn embedding_tasks_predict(
         &self,
-        request: Request<EmbeddingTasksRequest>,
+        mut request: Request<EmbeddingTasksRequest>,
     ) -> Result<Response<EmbeddingResults>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let br: &EmbeddingTasksRequest = request.get_ref();
         if br.texts.is_empty() {
@@ -68,17 +83,38 @@ impl NlpService for NlpServicer {
             "Routing embeddings tasks predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .embedding_tasks_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("EmbeddingTasksPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.embedding_tasks_predict(request).await
+        });
+        let (cache, model_id) = (route.cache.clone(), route.model_id.clone());
+        let single_flight = route.single_flight.clone();
+        let send = |request: Request<EmbeddingTasksRequest>| async move {
+            let len = request.get_ref().texts.len();
+            let batch_split = route.batch_split.clone().filter(|s| len > s.max_batch_size);
+            if let Some(batch_split) = batch_split {
+                let send =
+                    |route, request| shadow::call("EmbeddingTasksPredict", route, request, &call);
+                return split::call("EmbeddingTasksPredict", route, request, &batch_split, send)
+                    .await;
+            }
+            shadow::call("EmbeddingTasksPredict", route, request, call).await
+        };
+        cache::call("EmbeddingTasksPredict", cache, &model_id, request, |request| {
+            let single_flight = single_flight.as_deref();
+            let rpc = "EmbeddingTasksPredict";
+            deadline.run(self.in_flight.call(rpc, single_flight, &model_id, request, send))
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn embedding_task_predict(
         &self,
-        request: Request<EmbeddingTaskRequest>,
+        mut request: Request<EmbeddingTaskRequest>,
     ) -> Result<Response<EmbeddingResult>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let br = request.get_ref();
         if br.text.is_empty() {
@@ -88,17 +124,44 @@ impl NlpService for NlpServicer {
             "Routing embeddings task predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .embedding_task_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("EmbeddingTaskPredict", &route, request.metadata(), received);
+        let (cache, model_id) = (route.cache.clone(), route.model_id.clone());
+        let single_flight = route.single_flight.clone();
+        let at = deadline.at();
+        let call = deadline.propagate(|mut client, request| async move {
+            client.embedding_task_predict(request).await
+        });
+        let send = |request: Request<EmbeddingTaskRequest>| async move {
+            if let Some(micro_batching) = route.micro_batching.clone() {
+                let (metadata, _, request) = request.into_parts();
+                let EmbeddingTaskRequest { text, truncate_input_tokens } = request;
+                let caller = caller_metadata(&metadata);
+                let key = (route.model_id.clone(), truncate_input_tokens, caller);
+                let model_id = route.model_id.clone();
+                let send = move |texts, at| {
+                    embed_batch(route, metadata, texts, truncate_input_tokens, at)
+                };
+                let result =
+                    self.embeddings.submit(key, &model_id, &micro_batching, text, at, send);
+                return result.await.map(Response::new);
+            }
+            shadow::call("EmbeddingTaskPredict", route, request, call).await
+        };
+        cache::call("EmbeddingTaskPredict", cache, &model_id, request, |request| {
+            let single_flight = single_flight.as_deref();
+            let rpc = "EmbeddingTaskPredict";
+            deadline.run(self.in_flight.call(rpc, single_flight, &model_id, request, send))
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn rerank_tasks_predict(
         &self,
-        request: Request<RerankTasksRequest>,
+        mut request: Request<RerankTasksRequest>,
     ) -> Result<Response<RerankResults>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let rtr: &RerankTasksRequest = request.get_ref();
         if rtr.documents.is_empty() || rtr.queries.is_empty() {
@@ -108,17 +171,27 @@ impl NlpService for NlpServicer {
             "Routing rerank tasks predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .rerank_tasks_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("RerankTasksPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.rerank_tasks_predict(request).await
+        });
+        let len = request.get_ref().documents.len();
+        if let Some(batch_split) = route.batch_split.clone().filter(|s| len > s.max_batch_size) {
+            let send = |route, request| retry::call(route, request, &call);
+            return deadline
+                .run(split::call("RerankTasksPredict", route, request, &batch_split, send))
+                .await;
+        }
+        deadline.run(retry::call(route, request, call)).await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn rerank_task_predict(
         &self,
-        request: Request<RerankTaskRequest>,
+        mut request: Request<RerankTaskRequest>,
     ) -> Result<Response<RerankResult>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let rtr: &RerankTaskRequest = request.get_ref();
         if rtr.documents.is_empty() || rtr.query.is_empty() {
@@ -128,17 +201,20 @@ impl NlpService for NlpServicer {
             "Routing rerank task predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .rerank_task_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("RerankTaskPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.rerank_task_predict(request).await
+        });
+        deadline.run(retry::call(route, request, call)).await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn sentence_similarity_tasks_predict(
         &self,
-        request: Request<SentenceSimilarityTasksRequest>,
+        mut request: Request<SentenceSimilarityTasksRequest>,
     ) -> Result<Response<SentenceSimilarityResults>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let sstr: &SentenceSimilarityTasksRequest = request.get_ref();
         if sstr.source_sentences.is_empty() || sstr.sentences.is_empty() {
@@ -148,17 +224,27 @@ impl NlpService for NlpServicer {
             "Routing sentence similarity tasks predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .sentence_similarity_tasks_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("SentenceSimilarityTasksPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.sentence_similarity_tasks_predict(request).await
+        });
+        let len = request.get_ref().sentences.len();
+        if let Some(batch_split) = route.batch_split.clone().filter(|s| len > s.max_batch_size) {
+            let send = |route, request| retry::call(route, request, &call);
+            return deadline
+                .run(split::call("SentenceSimilarityTasksPredict", route, request, &batch_split, send))
+                .await;
+        }
+        deadline.run(retry::call(route, request, call)).await
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn sentence_similarity_task_predict(
         &self,
-        request: Request<SentenceSimilarityTaskRequest>,
+        mut request: Request<SentenceSimilarityTaskRequest>,
     ) -> Result<Response<SentenceSimilarityResult>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let sstr: &SentenceSimilarityTaskRequest = request.get_ref();
         if sstr.source_sentence.is_empty() || sstr.sentences.is_empty() {
@@ -168,10 +254,12 @@ impl NlpService for NlpServicer {
             "Routing sentence similarity task predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .sentence_similarity_task_predict(request)
-            .await
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("SentenceSimilarityTaskPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.sentence_similarity_task_predict(request).await
+        });
+        deadline.run(retry::call(route, request, call)).await
     }
 
     type BidiStreamingTokenClassificationTaskPredictStream =
@@ -217,11 +305,12 @@ impl NlpService for NlpServicer {
         Err(Status::unimplemented("not implemented"))
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(backend, retries))]
     async fn tokenization_task_predict(
         &self,
-        request: Request<TokenizationTaskRequest>,
+        mut request: Request<TokenizationTaskRequest>,
     ) -> Result<Response<TokenizationResults>, Status> {
+        let received = Instant::now();
         let model_id = extract_model_id(&request)?;
         let ttr: &TokenizationTaskRequest = request.get_ref();
         if ttr.text.is_empty() {
@@ -231,10 +320,61 @@ impl NlpService for NlpServicer {
             "Routing tokenization task predict request for Model ID {}",
             model_id
         );
-        self.client(model_id)
-            .await?
-            .tokenization_task_predict(request)
+        let route = self.route(&mut request).await?;
+        let deadline = Deadline::new("TokenizationTaskPredict", &route, request.metadata(), received);
+        let call = deadline.propagate(|mut client, request| async move {
+            client.tokenization_task_predict(request).await
+        });
+        let (single_flight, model_id) = (route.single_flight.clone(), route.model_id.clone());
+        let send = |request| retry::call(route, request, call);
+        let single_flight = single_flight.as_deref();
+        let rpc = "TokenizationTaskPredict";
+        deadline
+            .run(self.in_flight.call(rpc, single_flight, &model_id, request, send))
             .await
     }
 
 }
+
+/// Sends the `texts` of single embeddings requests for the model of `route`
+/// upstream as one batch, with the `metadata` of the request which started it,
+/// which all of them share, and by the deadline `at` (if any), and splits its
+/// results. Upstreams only count the
+/// input tokens of the whole batch, so each text's count is only known, and set,
+/// if the batch has a single text.
+async fn embed_batch(
+    route: Route<NlpServiceClient<UpstreamChannel>>,
+    metadata: MetadataMap,
+    texts: Vec<String>,
+    truncate_input_tokens: Option<i64>,
+    at: Option<Instant>,
+) -> Result<Vec<EmbeddingResult>, Status> {
+    let len = texts.len();
+    let request = Request::from_parts(
+        metadata,
+        Extensions::default(),
+        EmbeddingTasksRequest { texts, truncate_input_tokens },
+    )
+    .inject_context_span(&Span::current());
+    let deadline = Deadline::until("EmbeddingTasksPredict", &route, at);
+    let call = deadline.propagate(|mut client, request| async move {
+        client.embedding_tasks_predict(request).await
+    });
+    let results = deadline
+        .run(shadow::call("EmbeddingTasksPredict", route, request, call))
+        .await?
+        .into_inner();
+    let input_token_count = match len {
+        1 => results.input_token_count,
+        _ => 0,
+    };
+    let vectors = results.results.map_or(vec![], |results| results.vectors);
+    Ok(vectors
+        .into_iter()
+        .map(|vector| EmbeddingResult {
+            result: Some(vector),
+            producer_id: results.producer_id.clone(),
+            input_token_count,
+        })
+        .collect())
+}
@@ -0,0 +1,166 @@
+//! Retries of idempotent requests which fail with a transient error, limited by
+//! a retry budget.
+use std::{future::Future, sync::Arc, sync::Mutex, time::Duration};
+
+use rand::Rng;
+use tonic::{Extensions, Request, Response, Status};
+use tracing::{debug, Span};
+
+use crate::{clients::Route, rpc::failover, RetryBudget, RetryPolicy};
+
+/// Retry policy of a route, along with the budget of its service.
+#[derive(Debug, Clone)]
+pub(crate) struct Retry {
+    pub policy: Arc<RetryPolicy>,
+    pub budget: Arc<TokenBucket>,
+}
+
+/// Tokens of a [`RetryBudget`].
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    config: RetryBudget,
+    tokens: Mutex<f64>,
+}
+
+impl TokenBucket {
+    pub fn new(config: RetryBudget) -> Self {
+        Self {
+            tokens: Mutex::new(config.max_tokens as f64),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &RetryBudget {
+        &self.config
+    }
+
+    fn on_success(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.config.token_ratio).min(self.config.max_tokens as f64);
+    }
+
+    /// Takes a token for a failed attempt, returning whether it may be retried.
+    fn on_failure(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens - 1.0).max(0.0);
+        *tokens > self.config.max_tokens as f64 / 2.0
+    }
+}
+
+/// Sends `request` with `call` to the backends of `route` as [`failover::call`]
+/// does, retrying it according to the retry policy of `route` (if any). The
+/// number of retries is recorded in the current span.
+pub(crate) async fn call<C, T, R, F, Fut>(
+    mut route: Route<C>,
+    request: Request<T>,
+    call: F,
+) -> Result<Response<R>, Status>
+where
+    C: Clone,
+    T: Clone,
+    F: Fn(C, Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<R>, Status>>,
+{
+    let Some(Retry { policy, budget }) = route.retry.take() else {
+        return failover::call(route, request, call).await;
+    };
+    let (metadata, _, message) = request.into_parts();
+    let mut backoff = Duration::from_millis(policy.initial_backoff_ms);
+    let mut retries = 0;
+    loop {
+        let request = Request::from_parts(metadata.clone(), Extensions::default(), message.clone());
+        let result = failover::call(route.clone(), request, &call).await;
+        let status = match &result {
+            Ok(_) => {
+                budget.on_success();
+                break record(retries, result);
+            }
+            Err(status) if policy.retryable_status_codes.contains(&status.code()) => status,
+            Err(_) => break record(retries, result),
+        };
+        let allowed = budget.on_failure();
+        if retries + 1 >= policy.max_attempts {
+            break record(retries, result);
+        }
+        if !allowed {
+            debug!(
+                "Not retrying request for model_id {} as the retry budget is exhausted",
+                route.model_id
+            );
+            metrics::counter!(
+                "fmaas_router_retry_budget_exhausted_count",
+                "model" => route.model_id.clone()
+            )
+            .increment(1);
+            break record(retries, result);
+        }
+        let delay = rand::thread_rng().gen_range(Duration::ZERO..=backoff);
+        debug!(
+            "Retrying request for model_id {} in {}ms after {:?}: {}",
+            route.model_id,
+            delay.as_millis(),
+            status.code(),
+            status.message()
+        );
+        tokio::time::sleep(delay).await;
+        backoff = backoff
+            .mul_f64(policy.backoff_multiplier)
+            .min(Duration::from_millis(policy.max_backoff_ms));
+        retries += 1;
+        metrics::counter!("fmaas_router_retry_count", "model" => route.model_id.clone())
+            .increment(1);
+    }
+}
+
+fn record<T>(retries: u32, result: T) -> T {
+    Span::current().record("retries", retries);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(token_ratio: f64) -> TokenBucket {
+        TokenBucket::new(RetryBudget {
+            max_tokens: 10,
+            token_ratio,
+        })
+    }
+
+    #[test]
+    fn token_bucket_allows_retries_above_half() {
+        let budget = bucket(0.5);
+        for _ in 0..4 {
+            assert!(budget.on_failure());
+        }
+        // 5 tokens left, which is not more than half
+        assert!(!budget.on_failure());
+        for _ in 0..3 {
+            budget.on_success();
+        }
+        // 4 + 1.5 tokens
+        assert!(budget.on_failure());
+        assert!(!budget.on_failure());
+    }
+
+    #[test]
+    fn token_bucket_is_bounded() {
+        let budget = bucket(1.0);
+        for _ in 0..20 {
+            budget.on_success();
+        }
+        for _ in 0..4 {
+            assert!(budget.on_failure());
+        }
+        assert!(!budget.on_failure());
+        for _ in 0..20 {
+            budget.on_failure();
+        }
+        for _ in 0..7 {
+            budget.on_success();
+        }
+        assert!(budget.on_failure());
+        assert!(!budget.on_failure());
+    }
+}
@@ -0,0 +1,193 @@
+//! Shadowing of requests to the shadow upstream of a model, whose responses are
+//! compared with those of the model's backends but never returned to clients.
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+use tonic::{Extensions, Request, Response, Status};
+use tracing::debug;
+
+use crate::{
+    clients::Route,
+    pb::{
+        caikit_data_model::caikit_nlp::{EmbeddingResult, EmbeddingResults},
+        fmaas::{BatchedGenerationResponse, BatchedTokenizeResponse},
+    },
+    rpc::retry,
+};
+
+/// The parts of a response which are compared between a backend and the shadow.
+pub(crate) struct Summary {
+    /// Generated texts, compared one by one.
+    texts: Vec<String>,
+    token_count: i64,
+}
+
+/// Responses of RPCs which can be shadowed.
+pub(crate) trait Summarize {
+    fn summarize(&self) -> Summary;
+}
+
+impl Summarize for BatchedGenerationResponse {
+    fn summarize(&self) -> Summary {
+        Summary {
+            texts: self.responses.iter().map(|r| r.text.clone()).collect(),
+            token_count: self
+                .responses
+                .iter()
+                .map(|r| r.generated_token_count as i64)
+                .sum(),
+        }
+    }
+}
+
+impl Summarize for BatchedTokenizeResponse {
+    fn summarize(&self) -> Summary {
+        Summary {
+            texts: vec![],
+            token_count: self.responses.iter().map(|r| r.token_count as i64).sum(),
+        }
+    }
+}
+
+impl Summarize for EmbeddingResults {
+    fn summarize(&self) -> Summary {
+        Summary {
+            texts: vec![],
+            token_count: self.input_token_count,
+        }
+    }
+}
+
+impl Summarize for EmbeddingResult {
+    fn summarize(&self) -> Summary {
+        Summary {
+            texts: vec![],
+            token_count: self.input_token_count,
+        }
+    }
+}
+
+/// Sends `request` with `call` to the backends of `route` as [`retry::call`]
+/// does, and a copy of it to the shadow client of `route` (if any) in the
+/// background. The shadow's response is compared with that of the backend once
+/// both have completed, and discarded.
+pub(crate) async fn call<C, T, R, F, Fut>(
+    rpc: &'static str,
+    mut route: Route<C>,
+    request: Request<T>,
+    call: F,
+) -> Result<Response<R>, Status>
+where
+    C: Clone,
+    T: Clone,
+    R: Summarize + Send + 'static,
+    F: Fn(C, Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<R>, Status>> + Send + 'static,
+{
+    let Some(shadow) = route.shadow.take() else {
+        return retry::call(route, request, call).await;
+    };
+    let copy = Request::from_parts(
+        request.metadata().clone(),
+        Extensions::default(),
+        request.get_ref().clone(),
+    );
+    let shadow_call = call(shadow, copy);
+    let (tx, rx) = oneshot::channel();
+    let model_id = route.model_id.clone();
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let shadow = shadow_call.await;
+        let shadow_latency = start.elapsed();
+        // Nothing is sent if the request to the backend was cancelled
+        match rx.await {
+            Ok(Some((backend, latency))) => {
+                let shadow = shadow.map(|r| (r.get_ref().summarize(), shadow_latency));
+                compare(rpc, &model_id, backend, latency, shadow);
+            }
+            Ok(None) | Err(_) => count(rpc, &model_id, "skipped"),
+        }
+    });
+
+    let start = Instant::now();
+    let result = retry::call(route, request, call).await;
+    let backend = result.as_ref().ok().map(|r| r.get_ref().summarize());
+    let _ = tx.send(backend.map(|summary| (summary, start.elapsed())));
+    result
+}
+
+/// Records the differences between the responses of a backend and the shadow.
+fn compare(
+    rpc: &'static str,
+    model_id: &str,
+    backend: Summary,
+    backend_latency: Duration,
+    shadow: Result<(Summary, Duration), Status>,
+) {
+    let (shadow, shadow_latency) = match shadow {
+        Ok(shadow) => shadow,
+        Err(status) => {
+            debug!(
+                "Shadow {rpc} request for model {model_id} failed: {:?} {}",
+                status.code(),
+                status.message()
+            );
+            count(rpc, model_id, "error");
+            return;
+        }
+    };
+    let mismatched_texts = backend
+        .texts
+        .iter()
+        .zip(&shadow.texts)
+        .filter(|(backend, shadow)| backend != shadow)
+        .count()
+        + backend.texts.len().abs_diff(shadow.texts.len());
+    let token_count_delta = shadow.token_count - backend.token_count;
+    let latency_delta = shadow_latency.as_secs_f64() - backend_latency.as_secs_f64();
+    debug!(
+        "Shadow {rpc} response for model {model_id}: {mismatched_texts} of {} texts differ, \
+        token count delta {token_count_delta}, latency delta {:.0}ms",
+        backend.texts.len(),
+        latency_delta * 1000.0
+    );
+
+    metrics::counter!(
+        "fmaas_router_shadow_text_mismatch_count",
+        "rpc" => rpc,
+        "model" => model_id.to_string()
+    )
+    .increment(mismatched_texts as u64);
+    metrics::histogram!(
+        "fmaas_router_shadow_token_count_delta",
+        "rpc" => rpc,
+        "model" => model_id.to_string()
+    )
+    .record(token_count_delta as f64);
+    metrics::histogram!(
+        "fmaas_router_shadow_latency_delta_seconds",
+        "rpc" => rpc,
+        "model" => model_id.to_string()
+    )
+    .record(latency_delta);
+    let result = match mismatched_texts == 0 && token_count_delta == 0 {
+        true => "match",
+        false => "mismatch",
+    };
+    count(rpc, model_id, result);
+}
+
+/// Counts a shadowed request by its `result`, which is one of `match`,
+/// `mismatch`, `error` (the shadow failed) or `skipped` (the backend failed).
+fn count(rpc: &'static str, model_id: &str, result: &'static str) {
+    metrics::counter!(
+        "fmaas_router_shadow_request_count",
+        "rpc" => rpc,
+        "model" => model_id.to_string(),
+        "result" => result
+    )
+    .increment(1);
+}
@@ -0,0 +1,276 @@
+//! De-duplication of identical concurrent requests, which share one upstream
+//! call whose response is returned to all of them.
+use std::{
+    any::Any,
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+use tokio::sync::watch;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+use tracing::debug;
+
+use crate::{
+    rpc::{caller_metadata, CallerMetadata},
+    SingleFlightPolicy,
+};
+
+/// Outcome of an upstream call shared with the requests waiting for it, which
+/// is a `Result<(MetadataMap, R), Status>` for the response type `R` of its RPC.
+type Outcome = Arc<dyn Any + Send + Sync>;
+
+/// Upstream calls in flight in one service, by RPC, upstream model id, encoded
+/// request message and caller metadata, so that responses are only shared with
+/// callers which have the same credentials.
+#[derive(Debug)]
+pub(crate) struct SingleFlight {
+    service: &'static str,
+    in_flight: Mutex<HashMap<Key, watch::Receiver<Option<Outcome>>>>,
+}
+
+type Key = (&'static str, String, Vec<u8>, CallerMetadata);
+
+/// Removes the call with `key` from those in flight once it completes or is
+/// cancelled, so that later requests make a new one.
+struct InFlight<'a> {
+    single_flight: &'a SingleFlight,
+    key: Key,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.single_flight
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+impl SingleFlight {
+    pub fn new(service: &'static str) -> Self {
+        Self {
+            service,
+            in_flight: Mutex::default(),
+        }
+    }
+
+    /// Sends `request` for `rpc` to `model_id` with `send`, unless an identical
+    /// request is already in flight, in which case its response is returned
+    /// instead. If the request which made the call is cancelled, one of those
+    /// waiting for it makes it again. Requests are sent as they are unless
+    /// `policy` de-duplicates `rpc`. Each request is bounded by its own deadline,
+    /// whether it makes the call or waits for it, so callers apply it to the whole
+    /// of this call rather than only to `send`.
+    pub async fn call<T, R, F, Fut>(
+        &self,
+        rpc: &'static str,
+        policy: Option<&SingleFlightPolicy>,
+        model_id: &str,
+        request: Request<T>,
+        send: F,
+    ) -> Result<Response<R>, Status>
+    where
+        T: Message,
+        R: Clone + Send + Sync + 'static,
+        F: FnOnce(Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<R>, Status>>,
+    {
+        if !policy.is_some_and(|policy| policy.deduplicates(rpc)) {
+            return send(request).await;
+        }
+        let key = (
+            rpc,
+            model_id.to_string(),
+            request.get_ref().encode_to_vec(),
+            caller_metadata(request.metadata()),
+        );
+        let tx = loop {
+            let mut rx = match self.in_flight.lock().unwrap().entry(key.clone()) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => {
+                    let (tx, rx) = watch::channel(None);
+                    entry.insert(rx);
+                    break tx;
+                }
+            };
+            // The channel is closed without an outcome if the call was cancelled
+            let outcome = match rx.wait_for(Option::is_some).await {
+                Ok(outcome) => outcome.clone().unwrap(),
+                Err(_) => continue,
+            };
+            debug!("Sharing in-flight {rpc} response for model_id {model_id}");
+            metrics::counter!(
+                "fmaas_router_deduplicated_request_count",
+                "service" => self.service,
+                "rpc" => rpc,
+                "model" => model_id.to_string()
+            )
+            .increment(1);
+            let outcome = outcome
+                .downcast_ref::<Result<(MetadataMap, R), Status>>()
+                .unwrap()
+                .clone();
+            return outcome.map(|(metadata, message)| {
+                let mut response = Response::new(message);
+                *response.metadata_mut() = metadata;
+                response
+            });
+        };
+        let in_flight = InFlight {
+            single_flight: self,
+            key,
+        };
+        let result = send(request).await;
+        // No more requests can wait for the call once it is removed, so the
+        // outcome is only shared with those which already do
+        drop(in_flight);
+        if tx.receiver_count() == 0 {
+            return result;
+        }
+        let outcome = match &result {
+            Ok(response) => Ok((response.metadata().clone(), response.get_ref().clone())),
+            // The deadline of this request isn't that of the others, which make
+            // the call again as if it had been cancelled
+            Err(status) if status.code() == Code::DeadlineExceeded => return result,
+            Err(status) => Err(status.clone()),
+        };
+        tx.send_replace(Some(Arc::new(outcome)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    /// Sends `requests` concurrently, counting the upstream calls made in `calls`.
+    async fn call_all(
+        single_flight: &SingleFlight,
+        policy: Option<&SingleFlightPolicy>,
+        requests: &[&str],
+        status: Option<Code>,
+        calls: &AtomicUsize,
+    ) -> Vec<Result<String, Status>> {
+        join_all(requests.iter().map(|request| {
+            let request = Request::new(request.to_string());
+            single_flight.call("Tokenize", policy, "model", request, |request| async move {
+                calls.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                match status {
+                    Some(code) => Err(Status::new(code, "failed")),
+                    None => Ok(Response::new(request.into_inner())),
+                }
+            })
+        }))
+        .await
+        .into_iter()
+        .map(|result| result.map(Response::into_inner))
+        .collect()
+    }
+
+    fn policy() -> SingleFlightPolicy {
+        SingleFlightPolicy {
+            rpcs: vec!["Tokenize".to_string()],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shares_identical_calls() {
+        let single_flight = SingleFlight::new("generation");
+        let calls = AtomicUsize::new(0);
+        let requests = ["a", "b", "a", "a"];
+        let results = call_all(&single_flight, Some(&policy()), &requests, None, &calls).await;
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, requests);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(single_flight.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shares_errors_but_not_deadlines() {
+        let single_flight = SingleFlight::new("generation");
+        let calls = AtomicUsize::new(0);
+        let policy = policy();
+        let status = Some(Code::Unavailable);
+        let results = call_all(&single_flight, Some(&policy), &["a", "a"], status, &calls).await;
+        assert!(results
+            .iter()
+            .all(|r| r.as_ref().unwrap_err().code() == Code::Unavailable));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        let calls = AtomicUsize::new(0);
+        let status = Some(Code::DeadlineExceeded);
+        call_all(&single_flight, Some(&policy), &["a", "a"], status, &calls).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_calls_as_they_are_without_policy() {
+        let single_flight = SingleFlight::new("generation");
+        let other = SingleFlightPolicy {
+            rpcs: vec!["EmbeddingTasksPredict".to_string()],
+        };
+        for policy in [None, Some(&other)] {
+            let calls = AtomicUsize::new(0);
+            call_all(&single_flight, policy, &["a", "a"], None, &calls).await;
+            assert_eq!(calls.load(Ordering::Relaxed), 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shares_calls_only_with_the_same_metadata() {
+        let single_flight = SingleFlight::new("generation");
+        let calls = AtomicUsize::new(0);
+        let policy = policy();
+        let results = join_all(["a", "b", "a"].map(|authorization| {
+            let mut request = Request::new("request".to_string());
+            let value = format!("Bearer {authorization}").parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+            single_flight.call("Tokenize", Some(&policy), "model", request, |_| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(Response::new(authorization.to_string()))
+            })
+        }))
+        .await;
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|r| r.unwrap().into_inner())
+            .collect();
+        assert_eq!(results, ["a", "b", "a"]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_requests_are_bounded_by_their_own_deadline() {
+        let single_flight = SingleFlight::new("generation");
+        let policy = policy();
+        let call = |timeout: Duration| {
+            let request = Request::new("request".to_string());
+            let call = single_flight.call("Tokenize", Some(&policy), "model", request, |_| async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Ok(Response::new("response".to_string()))
+            });
+            tokio::time::timeout(timeout, call)
+        };
+        let start = tokio::time::Instant::now();
+        let (leader, follower) = tokio::join!(call(Duration::from_secs(10)), async {
+            let result = call(Duration::from_millis(100)).await;
+            (result, start.elapsed())
+        });
+        assert_eq!(leader.unwrap().unwrap().into_inner(), "response");
+        let (follower, elapsed) = follower;
+        assert!(follower.is_err());
+        assert_eq!(elapsed, Duration::from_millis(100));
+    }
+}
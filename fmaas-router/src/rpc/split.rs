@@ -0,0 +1,486 @@
+//! Splitting of large batches into sub-batches which are sent upstream
+//! concurrently, so that they can be served by different replicas rather than
+//! queueing on one.
+use std::future::Future;
+
+use futures::future::{join_all, try_join_all};
+use tonic::{Extensions, Request, Response, Status};
+use tracing::{debug, warn};
+
+use crate::{
+    clients::Route,
+    pb::{
+        caikit::runtime::nlp::{
+            EmbeddingTasksRequest, RerankTasksRequest, SentenceSimilarityTasksRequest,
+        },
+        caikit_data_model::{
+            caikit_nlp::{
+                EmbeddingResults, RerankResults, RerankScores, SentenceSimilarityResults,
+                SentenceSimilarityScores,
+            },
+            common::ListOfVector1D,
+        },
+        fmaas::{
+            BatchedGenerationRequest, BatchedGenerationResponse, GenerationResponse, StopReason,
+        },
+    },
+    BatchSplit, SplitFailure,
+};
+
+/// Requests with a batch of items which can be split, whose responses to the
+/// sub-batches are merged into the response to the whole batch.
+pub(crate) trait Split: Sized {
+    type Response;
+
+    /// Number of items in the batch.
+    fn len(&self) -> usize;
+
+    /// Requests for consecutive chunks of at most `size` items of the batch.
+    fn split(&self, size: usize) -> Vec<Self>;
+
+    /// Merges the responses to the requests which the batch was split into by
+    /// [`Self::split`] with `size`, in order.
+    fn merge(&self, size: usize, parts: Vec<Self::Response>) -> Self::Response;
+
+    /// Stand-in for the response to a sub-batch of `len` items which failed, if
+    /// the response can tell failed items apart.
+    fn failed(_len: usize) -> Option<Self::Response> {
+        None
+    }
+}
+
+impl Split for BatchedGenerationRequest {
+    type Response = BatchedGenerationResponse;
+
+    fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn split(&self, size: usize) -> Vec<Self> {
+        self.requests
+            .chunks(size)
+            .map(|requests| Self {
+                model_id: self.model_id.clone(),
+                prefix_id: self.prefix_id.clone(),
+                adapter_id: self.adapter_id.clone(),
+                requests: requests.to_vec(),
+                params: self.params.clone(),
+            })
+            .collect()
+    }
+
+    fn merge(&self, _size: usize, parts: Vec<Self::Response>) -> Self::Response {
+        BatchedGenerationResponse {
+            responses: parts.into_iter().flat_map(|part| part.responses).collect(),
+        }
+    }
+
+    fn failed(len: usize) -> Option<Self::Response> {
+        let response = GenerationResponse {
+            stop_reason: StopReason::Error as i32,
+            ..Default::default()
+        };
+        Some(BatchedGenerationResponse {
+            responses: vec![response; len],
+        })
+    }
+}
+
+impl Split for EmbeddingTasksRequest {
+    type Response = EmbeddingResults;
+
+    fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    fn split(&self, size: usize) -> Vec<Self> {
+        self.texts
+            .chunks(size)
+            .map(|texts| Self {
+                texts: texts.to_vec(),
+                truncate_input_tokens: self.truncate_input_tokens,
+            })
+            .collect()
+    }
+
+    fn merge(&self, _size: usize, parts: Vec<Self::Response>) -> Self::Response {
+        let producer_id = parts.first().and_then(|part| part.producer_id.clone());
+        let input_token_count = parts.iter().map(|part| part.input_token_count).sum();
+        let vectors = parts
+            .into_iter()
+            .flat_map(|part| part.results.map_or(vec![], |results| results.vectors))
+            .collect();
+        EmbeddingResults {
+            results: Some(ListOfVector1D { vectors }),
+            producer_id,
+            input_token_count,
+        }
+    }
+}
+
+/// Documents are split, and each sub-batch is ranked against all the queries.
+/// The scores of each query are ranked again across the sub-batches, so the
+/// `top_n` of the request is only applied once they are merged. The
+/// `input_token_count` is that of all the upstream calls, which count the tokens
+/// of the queries once per sub-batch.
+impl Split for RerankTasksRequest {
+    type Response = RerankResults;
+
+    fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    fn split(&self, size: usize) -> Vec<Self> {
+        self.documents
+            .chunks(size)
+            .map(|documents| Self {
+                queries: self.queries.clone(),
+                documents: documents.to_vec(),
+                top_n: None,
+                truncate_input_tokens: self.truncate_input_tokens,
+                return_documents: self.return_documents,
+                return_queries: self.return_queries,
+                return_text: self.return_text,
+            })
+            .collect()
+    }
+
+    fn merge(&self, size: usize, parts: Vec<Self::Response>) -> Self::Response {
+        let producer_id = parts.first().and_then(|part| part.producer_id.clone());
+        let input_token_count = parts.iter().map(|part| part.input_token_count).sum();
+        let mut results: Vec<RerankScores> = vec![];
+        for (i, part) in parts.into_iter().enumerate() {
+            // Indices of documents are relative to their sub-batch
+            let offset = (i * size) as i64;
+            for (j, mut query) in part.results.into_iter().enumerate() {
+                query
+                    .scores
+                    .iter_mut()
+                    .for_each(|score| score.index += offset);
+                match results.get_mut(j) {
+                    Some(merged) => merged.scores.append(&mut query.scores),
+                    None => results.push(query),
+                }
+            }
+        }
+        for query in &mut results {
+            query.scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+            if let Some(top_n) = self.top_n.filter(|top_n| *top_n > 0) {
+                query.scores.truncate(top_n as usize);
+            }
+        }
+        RerankResults {
+            results,
+            producer_id,
+            input_token_count,
+        }
+    }
+}
+
+/// Sentences are split, and each sub-batch is compared with all the source
+/// sentences. The `input_token_count` is that of all the upstream calls, which
+/// count the tokens of the source sentences once per sub-batch.
+impl Split for SentenceSimilarityTasksRequest {
+    type Response = SentenceSimilarityResults;
+
+    fn len(&self) -> usize {
+        self.sentences.len()
+    }
+
+    fn split(&self, size: usize) -> Vec<Self> {
+        self.sentences
+            .chunks(size)
+            .map(|sentences| Self {
+                source_sentences: self.source_sentences.clone(),
+                sentences: sentences.to_vec(),
+                truncate_input_tokens: self.truncate_input_tokens,
+            })
+            .collect()
+    }
+
+    fn merge(&self, _size: usize, parts: Vec<Self::Response>) -> Self::Response {
+        let producer_id = parts.first().and_then(|part| part.producer_id.clone());
+        let input_token_count = parts.iter().map(|part| part.input_token_count).sum();
+        let mut results: Vec<SentenceSimilarityScores> = vec![];
+        for part in parts {
+            for (j, mut source) in part.results.into_iter().enumerate() {
+                match results.get_mut(j) {
+                    Some(merged) => merged.scores.append(&mut source.scores),
+                    None => results.push(source),
+                }
+            }
+        }
+        SentenceSimilarityResults {
+            results,
+            producer_id,
+            input_token_count,
+        }
+    }
+}
+
+/// Splits the batch of `request` into sub-batches of at most the size allowed by
+/// `split`, which are each sent to `route` with `send`, all at once. Their
+/// responses are reassembled in order, failing as configured by `split` if any
+/// of them failed.
+pub(crate) async fn call<C, T, F, Fut>(
+    rpc: &'static str,
+    route: Route<C>,
+    request: Request<T>,
+    split: &BatchSplit,
+    send: F,
+) -> Result<Response<T::Response>, Status>
+where
+    C: Clone,
+    T: Split,
+    F: Fn(Route<C>, Request<T>) -> Fut,
+    Fut: Future<Output = Result<Response<T::Response>, Status>>,
+{
+    let size = split.max_batch_size;
+    let (metadata, _, message) = request.into_parts();
+    let parts = message.split(size);
+    debug!(
+        "Splitting {rpc} batch of {} items for model_id {} into {} sub-batches",
+        message.len(),
+        route.model_id,
+        parts.len()
+    );
+    metrics::counter!(
+        "fmaas_router_split_batch_count",
+        "rpc" => rpc,
+        "model" => route.model_id.clone()
+    )
+    .increment(1);
+    let calls = parts.into_iter().map(|part| {
+        let len = part.len();
+        let request = Request::from_parts(metadata.clone(), Extensions::default(), part);
+        let response = send(route.clone(), request);
+        async move { (len, response.await) }
+    });
+    let responses = match split.on_failure {
+        // The other sub-batches are cancelled as soon as one fails
+        SplitFailure::Fail => {
+            try_join_all(calls.map(|call| async move {
+                let (_, response) = call.await;
+                response
+            }))
+            .await?
+        }
+        SplitFailure::Partial => {
+            let results = join_all(calls).await;
+            if results.iter().all(|(_, result)| result.is_err()) {
+                let (_, first) = results.into_iter().next().unwrap();
+                return first;
+            }
+            results
+                .into_iter()
+                .map(|(len, result)| match result {
+                    Ok(response) => Ok(response),
+                    Err(status) => {
+                        warn!(
+                            "{rpc} sub-batch of {len} items for model_id {} failed: {status}",
+                            route.model_id
+                        );
+                        T::failed(len).map(Response::new).ok_or(status)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let mut responses = responses.into_iter();
+    // The response has the metadata of the first sub-batch's
+    let (metadata, first, extensions) = responses.next().unwrap().into_parts();
+    let parts = std::iter::once(first)
+        .chain(responses.map(Response::into_inner))
+        .collect();
+    Ok(Response::from_parts(
+        metadata,
+        message.merge(size, parts),
+        extensions,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{
+        caikit_data_model::{
+            caikit_nlp::RerankScore,
+            common::{vector1_d::Data, PyFloatSequence, Vector1D},
+        },
+        fmaas::GenerationRequest,
+    };
+
+    #[test]
+    fn generation_batches_are_reassembled_in_order() {
+        let request = BatchedGenerationRequest {
+            model_id: "model".to_string(),
+            prefix_id: Some("prefix".to_string()),
+            requests: (0..5)
+                .map(|i| GenerationRequest {
+                    text: i.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let parts = request.split(2);
+        let lens: Vec<_> = parts.iter().map(Split::len).collect();
+        assert_eq!(lens, [2, 2, 1]);
+        assert!(parts
+            .iter()
+            .all(|part| part.model_id == "model" && part.prefix_id.as_deref() == Some("prefix")));
+
+        let responses = parts
+            .iter()
+            .map(|part| BatchedGenerationResponse {
+                responses: part
+                    .requests
+                    .iter()
+                    .map(|request| GenerationResponse {
+                        text: request.text.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+            })
+            .collect();
+        let merged = request.merge(2, responses);
+        let texts: Vec<_> = merged.responses.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, ["0", "1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn failed_generation_sub_batches_are_errors() {
+        let failed = BatchedGenerationRequest::failed(3).unwrap();
+        assert_eq!(failed.responses.len(), 3);
+        assert!(failed
+            .responses
+            .iter()
+            .all(|response| response.stop_reason == StopReason::Error as i32));
+    }
+
+    fn vector(value: f64) -> Vector1D {
+        Vector1D {
+            data: Some(Data::DataPyfloatsequence(PyFloatSequence {
+                values: vec![value],
+            })),
+        }
+    }
+
+    #[test]
+    fn embeddings_are_concatenated() {
+        let request = EmbeddingTasksRequest {
+            texts: (0..5).map(|i| i.to_string()).collect(),
+            truncate_input_tokens: Some(10),
+        };
+        let parts = request.split(2);
+        assert_eq!(parts[2].texts, ["4"]);
+        assert!(parts
+            .iter()
+            .all(|part| part.truncate_input_tokens == Some(10)));
+
+        let responses = parts
+            .iter()
+            .map(|part| EmbeddingResults {
+                results: Some(ListOfVector1D {
+                    vectors: part
+                        .texts
+                        .iter()
+                        .map(|t| vector(t.parse().unwrap()))
+                        .collect(),
+                }),
+                producer_id: None,
+                input_token_count: part.texts.len() as i64,
+            })
+            .collect();
+        let merged = request.merge(2, responses);
+        let vectors = merged.results.unwrap().vectors;
+        assert_eq!(
+            vectors,
+            (0..5).map(|i| vector(i as f64)).collect::<Vec<_>>()
+        );
+        assert_eq!(merged.input_token_count, 5);
+    }
+
+    #[test]
+    fn rerank_scores_are_ranked_across_sub_batches() {
+        let request = RerankTasksRequest {
+            queries: vec!["q0".to_string(), "q1".to_string()],
+            documents: vec![Default::default(); 5],
+            top_n: Some(3),
+            ..Default::default()
+        };
+        let parts = request.split(2);
+        assert_eq!(parts.len(), 3);
+        assert!(parts
+            .iter()
+            .all(|part| part.top_n.is_none() && part.queries.len() == 2));
+
+        // Each query scores document i of the whole batch as i for q0 and -i for q1
+        let responses = parts
+            .iter()
+            .enumerate()
+            .map(|(p, part)| RerankResults {
+                results: ["q0", "q1"]
+                    .into_iter()
+                    .map(|query| RerankScores {
+                        query: query.to_string(),
+                        scores: (0..part.documents.len())
+                            .map(|i| {
+                                let index = (p * 2 + i) as f64;
+                                RerankScore {
+                                    index: i as i64,
+                                    score: if query == "q0" { index } else { -index },
+                                    ..Default::default()
+                                }
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+                producer_id: None,
+                input_token_count: 1,
+            })
+            .collect();
+        let merged = request.merge(2, responses);
+        let ranked = |j: usize| -> Vec<(i64, f64)> {
+            merged.results[j]
+                .scores
+                .iter()
+                .map(|score| (score.index, score.score))
+                .collect()
+        };
+        assert_eq!(merged.results.len(), 2);
+        assert_eq!(ranked(0), [(4, 4.0), (3, 3.0), (2, 2.0)]);
+        assert_eq!(ranked(1), [(0, 0.0), (1, -1.0), (2, -2.0)]);
+        assert_eq!(merged.input_token_count, 3);
+    }
+
+    #[test]
+    fn sentence_similarity_scores_are_concatenated() {
+        let request = SentenceSimilarityTasksRequest {
+            source_sentences: vec!["s0".to_string(), "s1".to_string()],
+            sentences: (0..3).map(|i| i.to_string()).collect(),
+            truncate_input_tokens: None,
+        };
+        let parts = request.split(2);
+        assert!(parts.iter().all(|part| part.source_sentences.len() == 2));
+
+        let responses = parts
+            .iter()
+            .map(|part| SentenceSimilarityResults {
+                results: (0..2)
+                    .map(|s| SentenceSimilarityScores {
+                        scores: part
+                            .sentences
+                            .iter()
+                            .map(|t| (s * 10) as f64 + t.parse::<f64>().unwrap())
+                            .collect(),
+                    })
+                    .collect(),
+                producer_id: None,
+                input_token_count: 0,
+            })
+            .collect();
+        let merged = request.merge(2, responses);
+        assert_eq!(merged.results[0].scores, [0.0, 1.0, 2.0]);
+        assert_eq!(merged.results[1].scores, [10.0, 11.0, 12.0]);
+    }
+}
@@ -1,6 +1,8 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use axum::{routing::get, Router};
+use axum::{routing::get, Json, Router};
+use futures::future::ready;
+use metrics_exporter_prometheus::PrometheusBuilder;
 use tokio::{fs::read, signal, time::sleep};
 use tonic::transport::{
     server::RoutesBuilder, Certificate, ClientTlsConfig, Identity, Server, ServerTlsConfig,
@@ -8,11 +10,14 @@ use tonic::transport::{
 use tracing::info;
 
 use crate::{
+    admin,
+    clients::Upstreams,
     pb::{
         caikit::runtime::nlp::nlp_service_server::NlpServiceServer,
         fmaas::generation_service_server::GenerationServiceServer,
         caikit::runtime::info::info_service_server::InfoServiceServer
     },
+    reload::watch_model_map,
     rpc::{generation::GenerationServicer, info::InfoServicer, nlp::NlpServicer},
     ModelMap,
 };
@@ -26,17 +31,27 @@ pub async fn run(
     default_target_port: u16,
     upstream_tls: bool,
     upstream_tls_ca_cert: Option<String>,
+    model_map_path: PathBuf,
+    model_map_reload_interval: Option<Duration>,
     model_map: ModelMap,
+    admin_token: Option<String>,
+    admin_persist: bool,
 ) {
     let mut builder = Server::builder();
 
+    // Install the metrics recorder, rendered by the HTTP server
+    let prom_handle = PrometheusBuilder::new()
+        .install_recorder()
+        .expect("failed to install metrics recorder");
+
     // Configure TLS if requested
-    let mut client_tls = upstream_tls.then_some(ClientTlsConfig::new());
+    // The client TLS config is also used for models configured with upstream TLS
+    // individually, so it is populated even if upstream TLS is off by default.
+    let mut client_tls = ClientTlsConfig::new();
     if let Some(cert_path) = upstream_tls_ca_cert {
         info!("Configuring TLS for outgoing connections to model servers");
         let cert_pem = load_pem(cert_path, "cert").await;
-        let cert = Certificate::from_pem(cert_pem);
-        client_tls = client_tls.map(|c| c.ca_certificate(cert));
+        client_tls = client_tls.ca_certificate(Certificate::from_pem(cert_pem));
     }
     if let Some((cert_path, key_path)) = tls_key_pair {
         info!("Configuring Server TLS for incoming connections");
@@ -44,9 +59,7 @@ pub async fn run(
         let cert_pem = load_pem(cert_path, "cert").await;
         let key_pem = load_pem(key_path, "key").await;
         let identity = Identity::from_pem(cert_pem, key_pem);
-        if upstream_tls {
-            client_tls = client_tls.map(|c| c.identity(identity.clone()));
-        }
+        client_tls = client_tls.identity(identity.clone());
         tls_config = tls_config.identity(identity);
         if let Some(ca_cert_path) = tls_client_ca_cert {
             info!("Configuring TLS trust certificate (mTLS) for incoming connections");
@@ -60,24 +73,33 @@ pub async fn run(
         panic!("Upstream TLS enabled without any certificates");
     }
 
+    // Create upstream clients and watch the model map for changes. All services
+    // are enabled so that models can be added to any section by a reload.
+    // Upstreams which can't be reached yet are retried in the background.
+    let upstreams = Arc::new(Upstreams::new(default_target_port, upstream_tls, client_tls));
+    upstreams
+        .apply(&model_map)
+        .await
+        .expect("Error creating upstream service clients");
+    tokio::spawn(upstreams.clone().retry_unavailable());
+    tokio::spawn(watch_model_map(
+        model_map_path.clone(),
+        model_map_reload_interval,
+        model_map,
+        upstreams.clone(),
+    ));
+
     // Build and start gRPC server in background task
     let mut routes_builder = RoutesBuilder::default();
-    if let Some(model_map) = model_map.generation() {
-        info!("Enabling GenerationService");
-        let generation_servicer =
-            GenerationServicer::new(default_target_port, client_tls.as_ref(), model_map).await;
-        routes_builder.add_service(GenerationServiceServer::new(generation_servicer));
-    }
-    if let Some(model_map) = model_map.embeddings() {
-        info!("Enabling NlpService");
-        let nlp_servicer =
-            NlpServicer::new(default_target_port, client_tls.as_ref(), model_map).await;
-        routes_builder.add_service(NlpServiceServer::new(nlp_servicer));
-        info!("Enabling InfoService");
-        let info_servicer =
-        InfoServicer::new(default_target_port, client_tls.as_ref(), model_map).await;
-        routes_builder.add_service(InfoServiceServer::new(info_servicer));
-    }
+    info!("Enabling GenerationService");
+    let generation_servicer = GenerationServicer::new(upstreams.generation.clone());
+    routes_builder.add_service(GenerationServiceServer::new(generation_servicer));
+    info!("Enabling NlpService");
+    let nlp_servicer = NlpServicer::new(upstreams.nlp.clone());
+    routes_builder.add_service(NlpServiceServer::new(nlp_servicer));
+    info!("Enabling InfoService");
+    let info_servicer = InfoServicer::new(upstreams.info.clone());
+    routes_builder.add_service(InfoServiceServer::new(info_servicer));
     let grpc_server = builder
         .add_routes(routes_builder.routes())
         .serve_with_shutdown(grpc_addr, shutdown_signal());
@@ -98,7 +120,16 @@ pub async fn run(
     }
 
     // Build and await on the HTTP server
-    let app = Router::new().route("/health", get(health));
+    let breakers = upstreams.clone();
+    let mut app = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(move || ready(prom_handle.render())))
+        .route("/breakers", get(move || ready(Json(breakers.circuit_breakers()))));
+    if let Some(token) = admin_token {
+        info!("Enabling admin API");
+        let persist_path = admin_persist.then_some(model_map_path);
+        app = app.merge(admin::router(upstreams.clone(), token, persist_path));
+    }
 
     let server = axum::Server::bind(&http_addr)
         .serve(app.into_make_service())